@@ -0,0 +1,256 @@
+use serde_json::{json, Map as JsonMap, Value as JsonValue};
+
+use crate::config::{Severity, TriggerWhen};
+use crate::rmq::Unit;
+
+pub const BROKER_UNREACHABLE_TRIGGER_TYPE: &str = "broker unreachable";
+
+/// An alert or a recovery, independent of how it's delivered
+#[derive(Debug, Clone)]
+pub enum Alert {
+    ThresholdPassed {
+        broker: String,
+        broker_name: String,
+        queue_name: String,
+        vhost: String,
+        threshold: f64,
+        current_value: f64,
+        unit: Unit,
+        trigger_type: String,
+        trigger_when: TriggerWhen,
+        severity: Severity,
+        route: Option<String>,
+        message_template: Option<String>,
+        runbook_url: Option<String>,
+    },
+    ThresholdResolved {
+        broker: String,
+        broker_name: String,
+        queue_name: String,
+        vhost: String,
+        trigger_type: String,
+        breach_seconds: u64,
+        peak_value: f64,
+        unit: Unit,
+        route: Option<String>,
+    },
+    BrokerUnreachable {
+        broker: String,
+        broker_name: String,
+        failed_polls: u32,
+        seconds_without_data: u64,
+        route: Option<String>,
+        message_template: Option<String>,
+        runbook_url: Option<String>,
+    },
+    BrokerReachable {
+        broker: String,
+        broker_name: String,
+        unreachable_seconds: u64,
+        route: Option<String>,
+    },
+}
+
+impl Alert {
+    /// The queue or broker the alert is about
+    pub fn subject(&self) -> &str {
+        match self {
+            Alert::ThresholdPassed { queue_name, .. }
+            | Alert::ThresholdResolved { queue_name, .. } => queue_name,
+            Alert::BrokerUnreachable { broker, .. } | Alert::BrokerReachable { broker, .. } => {
+                broker
+            }
+        }
+    }
+
+    /// The address of the broker the alert comes from
+    pub fn broker(&self) -> &str {
+        match self {
+            Alert::ThresholdPassed { broker, .. }
+            | Alert::ThresholdResolved { broker, .. }
+            | Alert::BrokerUnreachable { broker, .. }
+            | Alert::BrokerReachable { broker, .. } => broker,
+        }
+    }
+
+    /// The name of the broker the alert comes from, as set in the config
+    pub fn broker_name(&self) -> &str {
+        match self {
+            Alert::ThresholdPassed { broker_name, .. }
+            | Alert::ThresholdResolved { broker_name, .. }
+            | Alert::BrokerUnreachable { broker_name, .. }
+            | Alert::BrokerReachable { broker_name, .. } => broker_name,
+        }
+    }
+
+    /// The name of the route the alert should be delivered through
+    pub fn route(&self) -> Option<&str> {
+        match self {
+            Alert::ThresholdPassed { route, .. }
+            | Alert::ThresholdResolved { route, .. }
+            | Alert::BrokerUnreachable { route, .. }
+            | Alert::BrokerReachable { route, .. } => route.as_deref(),
+        }
+    }
+
+    /// The template of the alert text set on the trigger, recoveries have none
+    pub fn message_template(&self) -> Option<&str> {
+        match self {
+            Alert::ThresholdPassed {
+                message_template, ..
+            }
+            | Alert::BrokerUnreachable {
+                message_template, ..
+            } => message_template.as_deref(),
+            Alert::ThresholdResolved { .. } | Alert::BrokerReachable { .. } => None,
+        }
+    }
+
+    pub fn runbook_url(&self) -> Option<&str> {
+        match self {
+            Alert::ThresholdPassed { runbook_url, .. }
+            | Alert::BrokerUnreachable { runbook_url, .. } => runbook_url.as_deref(),
+            Alert::ThresholdResolved { .. } | Alert::BrokerReachable { .. } => None,
+        }
+    }
+
+    pub fn vhost(&self) -> &str {
+        match self {
+            Alert::ThresholdPassed { vhost, .. } | Alert::ThresholdResolved { vhost, .. } => vhost,
+            Alert::BrokerUnreachable { .. } | Alert::BrokerReachable { .. } => "",
+        }
+    }
+
+    pub fn trigger_type(&self) -> &str {
+        match self {
+            Alert::ThresholdPassed { trigger_type, .. }
+            | Alert::ThresholdResolved { trigger_type, .. } => trigger_type,
+            Alert::BrokerUnreachable { .. } | Alert::BrokerReachable { .. } => {
+                BROKER_UNREACHABLE_TRIGGER_TYPE
+            }
+        }
+    }
+
+    /// The unit of the value and threshold, the number of failed polls for the broker
+    pub fn unit(&self) -> Unit {
+        match self {
+            Alert::ThresholdPassed { unit, .. } | Alert::ThresholdResolved { unit, .. } => *unit,
+            Alert::BrokerUnreachable { .. } | Alert::BrokerReachable { .. } => Unit::Count,
+        }
+    }
+
+    /// The severity of an alert, recoveries have none
+    pub fn severity(&self) -> Option<Severity> {
+        match self {
+            Alert::ThresholdPassed { severity, .. } => Some(*severity),
+            Alert::BrokerUnreachable { .. } => Some(Severity::Critical),
+            Alert::ThresholdResolved { .. } | Alert::BrokerReachable { .. } => None,
+        }
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        matches!(
+            self,
+            Alert::ThresholdResolved { .. } | Alert::BrokerReachable { .. }
+        )
+    }
+
+    /// The values of the alert by name, used to fill in templates. Values
+    /// which don't apply to the kind of alert are `null`.
+    pub fn template_values(&self) -> JsonMap<String, JsonValue> {
+        let (kind, queue, threshold, value, direction, duration_seconds) = match self {
+            Alert::ThresholdPassed {
+                queue_name,
+                threshold,
+                current_value,
+                trigger_when,
+                ..
+            } => (
+                "threshold_passed",
+                json!(queue_name),
+                json!(threshold),
+                json!(current_value),
+                json!(trigger_when.name()),
+                JsonValue::Null,
+            ),
+            Alert::ThresholdResolved {
+                queue_name,
+                breach_seconds,
+                peak_value,
+                ..
+            } => (
+                "threshold_resolved",
+                json!(queue_name),
+                JsonValue::Null,
+                json!(peak_value),
+                JsonValue::Null,
+                json!(breach_seconds),
+            ),
+            Alert::BrokerUnreachable {
+                failed_polls,
+                seconds_without_data,
+                ..
+            } => (
+                "broker_unreachable",
+                JsonValue::Null,
+                JsonValue::Null,
+                json!(failed_polls),
+                JsonValue::Null,
+                json!(seconds_without_data),
+            ),
+            Alert::BrokerReachable {
+                unreachable_seconds,
+                ..
+            } => (
+                "broker_reachable",
+                JsonValue::Null,
+                JsonValue::Null,
+                JsonValue::Null,
+                JsonValue::Null,
+                json!(unreachable_seconds),
+            ),
+        };
+
+        let vhost = match self.vhost() {
+            "" => JsonValue::Null,
+            vhost => json!(vhost),
+        };
+        let status = if self.is_resolved() {
+            "resolved"
+        } else {
+            "firing"
+        };
+
+        let mut values = JsonMap::new();
+        values.insert("kind".into(), json!(kind));
+        values.insert("status".into(), json!(status));
+        values.insert("severity".into(), json!(self.severity().map(|s| s.name())));
+        values.insert("queue".into(), queue);
+        values.insert("vhost".into(), vhost);
+        values.insert("broker".into(), json!(self.broker()));
+        values.insert("broker_name".into(), json!(self.broker_name()));
+        values.insert("trigger_type".into(), json!(self.trigger_type()));
+        values.insert("metric".into(), json!(self.trigger_type()));
+        values.insert("threshold".into(), threshold);
+        values.insert("value".into(), value);
+        values.insert("direction".into(), direction);
+        values.insert("unit".into(), json!(self.unit().name()));
+        values.insert(
+            "formatted_threshold".into(),
+            format_value(&values["threshold"], self.unit()),
+        );
+        values.insert(
+            "formatted_value".into(),
+            format_value(&values["value"], self.unit()),
+        );
+        values.insert("duration_seconds".into(), duration_seconds);
+        values.insert("runbook_url".into(), json!(self.runbook_url()));
+        values
+    }
+}
+
+fn format_value(value: &JsonValue, unit: Unit) -> JsonValue {
+    value
+        .as_f64()
+        .map_or(JsonValue::Null, |value| json!(unit.format(value)))
+}
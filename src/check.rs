@@ -0,0 +1,254 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::config::{BrokerConfig, Config, Secret, Trigger};
+use crate::rmq::{get_queue_info_with_failover, http_client, Unit};
+
+/// The shared triggers and the ones of each broker which has its own, along
+/// with how they're told apart in the problems
+fn trigger_sets(config: &Config) -> Vec<(String, &[Trigger])> {
+    let broker_sets = config.brokers.iter().filter_map(|broker| {
+        let triggers = broker.triggers.as_deref()?;
+        Some((trigger_set_label(broker), triggers))
+    });
+
+    std::iter::once((String::new(), config.triggers.as_slice()))
+        .chain(broker_sets)
+        .collect()
+}
+
+fn trigger_set_label(broker: &BrokerConfig) -> String {
+    match broker.triggers {
+        Some(_) => format!(" of broker {}", &broker.name),
+        None => String::new(),
+    }
+}
+
+/// Every secret in the config along with where it's set
+fn secrets(config: &Config) -> Vec<(String, &Secret)> {
+    let mut secrets = Vec::new();
+
+    for broker in &config.brokers {
+        secrets.push((
+            format!("password of broker {}", &broker.name),
+            &broker.rabbitmq.password,
+        ));
+    }
+    if let Some(slack_config) = &config.slack {
+        if let Some(webhook_url) = &slack_config.webhook_url {
+            secrets.push(("Slack webhook_url".to_string(), webhook_url));
+        }
+        if let Some(bot_token) = &slack_config.bot_token {
+            secrets.push(("Slack bot_token".to_string(), bot_token));
+        }
+    }
+    for webhook_config in &config.webhooks {
+        secrets.push((
+            format!("url of webhook {}", &webhook_config.name),
+            &webhook_config.url,
+        ));
+        for (name, value) in &webhook_config.headers {
+            secrets.push((
+                format!("header {} of webhook {}", name, &webhook_config.name),
+                value,
+            ));
+        }
+    }
+    for (name, route) in &config.routes {
+        if let Some(slack_webhook_url) = &route.slack_webhook_url {
+            secrets.push((
+                format!("slack_webhook_url of route {}", name),
+                slack_webhook_url,
+            ));
+        }
+    }
+
+    secrets
+}
+
+fn describe_trigger(set_label: &str, index: usize, trigger: &Trigger) -> String {
+    let data = trigger.data();
+    let target = data
+        .queue
+        .as_deref()
+        .or(data.queue_pattern.as_ref().map(|pattern| pattern.as_str()))
+        .or(data.queue_regex.as_ref().map(|regex| regex.as_str()))
+        .unwrap_or("all queues");
+
+    format!(
+        "Trigger #{}{} ({} on {})",
+        index + 1,
+        set_label,
+        trigger.name(),
+        target
+    )
+}
+
+/// Checks the config for mistakes which parse fine but can't work as
+/// intended. Returns a description of every problem found.
+pub fn check_config(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if config.settings.poll_seconds == 0 {
+        problems.push("poll_seconds has to be greater than 0".to_string());
+    }
+
+    let max_poll_age = config
+        .server
+        .as_ref()
+        .and_then(|server_config| server_config.max_poll_age_seconds);
+    if let Some(max_poll_age) = max_poll_age {
+        if max_poll_age <= config.settings.poll_seconds {
+            problems.push(format!(
+                "max_poll_age_seconds ({}) has to be greater than poll_seconds ({}), otherwise the health checks fail between polls",
+                max_poll_age, config.settings.poll_seconds
+            ));
+        }
+    }
+
+    if let Some(slack_config) = &config.slack {
+        if slack_config.icon_url.is_some() && slack_config.icon_emoji.is_some() {
+            problems.push(
+                "Slack config sets both icon_url and icon_emoji, only one can be used".to_string(),
+            );
+        }
+    }
+
+    for (set_label, triggers) in trigger_sets(config) {
+        check_triggers(&set_label, triggers, &mut problems);
+    }
+
+    problems
+}
+
+fn check_triggers(set_label: &str, triggers: &[Trigger], problems: &mut Vec<String>) {
+    for (index, trigger) in triggers.iter().enumerate() {
+        let data = trigger.data();
+        let can_be_negative = trigger.stat_type().unit() == Unit::PerSecond;
+        let thresholds = [
+            ("warning threshold", data.warning),
+            ("critical threshold", data.critical),
+            ("clear_threshold", data.clear_threshold),
+        ];
+        for (name, value) in thresholds {
+            if value.is_some_and(|value| value < 0.0) && !can_be_negative {
+                problems.push(format!(
+                    "{} has a negative {}, the value can't be below 0",
+                    describe_trigger(set_label, index, trigger),
+                    name
+                ));
+            }
+        }
+    }
+
+    // Triggers selecting the same queues with the same type and route share
+    // the key of their alerts, whatever their thresholds, so they'd overwrite
+    // each other's state
+    let mut seen_triggers: HashMap<String, usize> = HashMap::new();
+    for (index, trigger) in triggers.iter().enumerate() {
+        let data = trigger.data();
+        let key = format!(
+            "{}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}",
+            trigger.name(),
+            data.queue,
+            data.queue_pattern.as_ref().map(|pattern| pattern.as_str()),
+            data.queue_regex.as_ref().map(|regex| regex.as_str()),
+            data.exclude
+                .iter()
+                .map(|pattern| pattern.as_str())
+                .collect::<Vec<_>>(),
+            data.vhost,
+            data.route,
+        );
+        match seen_triggers.get(&key) {
+            Some(first_index) => problems.push(format!(
+                "{} has the same type, queue selection, vhost and route as trigger #{}, the two would share the state of their alerts, give one of them a different route or use warning and critical thresholds",
+                describe_trigger(set_label, index, trigger),
+                first_index + 1
+            )),
+            None => {
+                seen_triggers.insert(key, index);
+            }
+        }
+    }
+}
+
+/// Checks the parts of the config which depend on the machine the monitor
+/// runs on, i.e. that the secrets can be read and the TLS files exist.
+/// Returns a description of every problem found.
+pub fn check_environment(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (name, secret) in secrets(config) {
+        if let Err(error) = secret.value() {
+            problems.push(format!("{} can't be read: {:#}", name, error));
+        }
+    }
+
+    for broker in &config.brokers {
+        let rmq_config = &broker.rabbitmq;
+        let tls_files = [
+            ("ca_file", &rmq_config.ca_file),
+            ("client_cert", &rmq_config.client_cert),
+            ("client_key", &rmq_config.client_key),
+        ];
+        for (name, path) in tls_files {
+            if let Some(path) = path.as_ref().filter(|path| !path.is_file()) {
+                problems.push(format!(
+                    "{} {} of broker {} doesn't exist",
+                    name,
+                    path.display(),
+                    &broker.name
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Checks that the queues named in the triggers exist on the brokers
+pub async fn check_queues_exist(config: &Config) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    for broker in &config.brokers {
+        problems.extend(check_broker_queues_exist(config, broker).await?);
+    }
+
+    Ok(problems)
+}
+
+async fn check_broker_queues_exist(config: &Config, broker: &BrokerConfig) -> Result<Vec<String>> {
+    let client = http_client(
+        &broker.rabbitmq,
+        Duration::from_secs(config.settings.request_timeout_seconds),
+    )?;
+    let (_, queue_info) = get_queue_info_with_failover(&client, &broker.rabbitmq, 0)
+        .await
+        .with_context(|| format!("Could not fetch queue info of {}", &broker.name))?;
+    let set_label = trigger_set_label(broker);
+
+    let problems = config
+        .broker_triggers(broker)
+        .iter()
+        .enumerate()
+        .filter_map(|(index, trigger)| {
+            let data = trigger.data();
+            let queue = data.queue.as_ref()?;
+            let exists = queue_info.iter().any(|qi| {
+                &qi.name == queue && data.vhost.as_ref().is_none_or(|vhost| &qi.vhost == vhost)
+            });
+            if exists {
+                return None;
+            }
+            Some(format!(
+                "{} refers to the queue {} which doesn't exist on broker {}",
+                describe_trigger(&set_label, index, trigger),
+                queue,
+                &broker.name
+            ))
+        })
+        .collect();
+
+    Ok(problems)
+}
@@ -1,5 +1,10 @@
-use anyhow::{Context, Result};
-use serde_derive::Deserialize;
+use anyhow::{bail, Context, Result};
+use glob::Pattern;
+use regex::Regex;
+use serde::{de::Error as _, Deserialize as _, Deserializer};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::read_to_string;
 use std::path::PathBuf;
 
@@ -7,48 +12,360 @@ use crate::rmq::StatType;
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
-    pub rabbitmq: RabbitMqConfig,
+    /// A single broker, moved to `brokers` when the config is read
+    pub rabbitmq: Option<RabbitMqConfig>,
+    #[serde(default)]
+    pub brokers: Vec<BrokerConfig>,
     pub settings: MonitorSettings,
-    pub slack: SlackConfig,
+    pub slack: Option<SlackConfig>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub routes: HashMap<String, RouteConfig>,
+    /// Triggers for all brokers which don't have their own
+    #[serde(default)]
     pub triggers: Vec<Trigger>,
+    pub broker_unreachable: Option<BrokerUnreachableTrigger>,
+    pub server: Option<ServerConfig>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct RabbitMqConfig {
     #[serde(default = "default_protocol")]
     pub protocol: String,
+    /// The first of `hosts` if that's set instead
+    #[serde(default)]
     pub host: String,
+    /// The nodes of a cluster, `host` if there's only one
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub failover: Failover,
     pub username: String,
-    pub password: String,
+    pub password: Secret,
     pub port: String,
-    pub vhost: String,
+    #[serde(default, alias = "vhost")]
+    pub vhosts: Vhosts,
+    /// PEM file with the CA certificates to trust instead of the system ones
+    pub ca_file: Option<PathBuf>,
+    /// PEM file with the client certificate, needs `client_key`
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key of `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// Accept any server certificate, only meant for testing
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+impl RabbitMqConfig {
+    /// The address of the broker, e.g. `https://rabbitmq.example.com:15672`
+    pub fn address(&self) -> String {
+        self.node_address(&self.host)
+    }
+
+    /// The address of a node of the cluster
+    pub fn node_address(&self, host: &str) -> String {
+        format!("{}://{}:{}", &self.protocol, host, &self.port)
+    }
+
+    /// Fills in `hosts` from `host` or the other way around and checks
+    /// the TLS options
+    fn normalize(&mut self) -> Result<()> {
+        match (self.host.is_empty(), self.hosts.first()) {
+            (true, None) => bail!("Either host or hosts has to be set"),
+            (true, Some(first_host)) => self.host = first_host.clone(),
+            (false, None) => self.hosts.push(self.host.clone()),
+            (false, Some(first_host)) if *first_host != self.host => {
+                bail!("host has to be the first of hosts if both are set")
+            }
+            (false, Some(_)) => {}
+        }
+
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            bail!("client_cert and client_key have to be set together");
+        }
+
+        Ok(())
+    }
+}
+
+/// Which node of a cluster is polled first
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Failover {
+    /// Stay with the node which answered last, try the next ones in order
+    /// when it fails
+    #[default]
+    InOrder,
+    /// Move on to the next node on every poll
+    RoundRobin,
+}
+
+/// A named broker with its own connection and optionally its own triggers
+#[derive(Deserialize, Debug)]
+pub struct BrokerConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub rabbitmq: RabbitMqConfig,
+    pub triggers: Option<Vec<Trigger>>,
+}
+
+impl Config {
+    /// The triggers which apply to the broker
+    pub fn broker_triggers<'a>(&'a self, broker: &'a BrokerConfig) -> &'a [Trigger] {
+        broker.triggers.as_deref().unwrap_or(&self.triggers)
+    }
+
+    /// All triggers, the shared ones and the ones of each broker
+    pub fn all_triggers(&self) -> impl Iterator<Item = &Trigger> {
+        self.triggers.iter().chain(
+            self.brokers
+                .iter()
+                .filter_map(|broker| broker.triggers.as_ref())
+                .flatten(),
+        )
+    }
 }
 
 fn default_protocol() -> String {
     "https".into()
 }
 
+/// A value which can be kept out of the config file, either the value
+/// itself, `{ env = "NAME" }` or `{ file = "/path" }`
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Secret {
+    Value(String),
+    Env { env: String },
+    File { file: PathBuf },
+}
+
+impl Secret {
+    /// The value of the secret. Files are read every time, so a rotated
+    /// secret is picked up without a restart. A trailing line break is dropped.
+    pub fn value(&self) -> Result<String> {
+        match self {
+            Secret::Value(value) => Ok(value.clone()),
+            Secret::Env { env } => std::env::var(env)
+                .with_context(|| format!("Could not read environment variable {}", env)),
+            Secret::File { file } => read_to_string(file)
+                .map(|value| value.trim_end_matches(['\r', '\n']).to_string())
+                .with_context(|| format!("Could not read secret file {}", file.display())),
+        }
+    }
+}
+
+/// Shows where the secret comes from, never the value
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Secret::Value(_) => write!(f, "Secret(..)"),
+            Secret::Env { env } => write!(f, "Secret(env {})", env),
+            Secret::File { file } => write!(f, "Secret(file {})", file.display()),
+        }
+    }
+}
+
+/// The vhosts to poll, either a single name, a list of names or "all"
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Vhosts {
+    Single(String),
+    List(Vec<String>),
+}
+
+impl Default for Vhosts {
+    fn default() -> Self {
+        Vhosts::Single("all".into())
+    }
+}
+
+impl Vhosts {
+    /// Returns `None` when queues from all vhosts should be fetched
+    pub fn names(&self) -> Option<Vec<&str>> {
+        match self {
+            Vhosts::Single(name) if name == "all" => None,
+            Vhosts::Single(name) => Some(vec![name.as_str()]),
+            Vhosts::List(names) if names.iter().any(|name| name == "all") => None,
+            Vhosts::List(names) => Some(names.iter().map(String::as_str).collect()),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct MonitorSettings {
     pub poll_seconds: u64,
     #[serde(default = "default_expiration")]
     pub msg_expiration_seconds: u64,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    /// How long a single request to the RabbitMQ API may take, retries
+    /// aren't included
+    #[serde(default = "default_request_timeout_seconds")]
+    pub request_timeout_seconds: u64,
+    /// File the state of the alerts is kept in between restarts
+    pub state_file: Option<PathBuf>,
 }
 
 fn default_expiration() -> u64 {
     600
 }
 
-#[derive(Deserialize, Debug)]
+fn default_request_timeout_seconds() -> u64 {
+    10
+}
+
+/// The embedded HTTP server exposing the metrics and health checks
+#[derive(Deserialize, Debug, Clone)]
+pub struct ServerConfig {
+    /// Address to listen on, e.g. `0.0.0.0:9419`
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    /// Polls older than this don't count for the health checks
+    pub max_poll_age_seconds: Option<u64>,
+}
+
+fn default_listen() -> String {
+    "0.0.0.0:9419".into()
+}
+
+impl ServerConfig {
+    /// The configured maximum age of a poll, by default three poll intervals
+    /// but at least 5 minutes, leaving room for the retries of a slow poll
+    pub fn max_poll_age(&self, poll_seconds: u64) -> u64 {
+        self.max_poll_age_seconds
+            .unwrap_or_else(|| (3 * poll_seconds).max(300))
+    }
+}
+
+/// How a failed poll of the RabbitMQ API is retried before the cycle is skipped
+#[derive(Deserialize, Debug, Clone)]
+pub struct RetryPolicy {
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    #[serde(default = "default_jitter")]
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: default_max_retries(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            jitter: default_jitter(),
+        }
+    }
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    500
+}
+
+fn default_max_backoff_ms() -> u64 {
+    10_000
+}
+
+fn default_jitter() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct SlackConfig {
-    pub webhook_url: String,
+    /// Legacy incoming webhook, ignored if `bot_token` is set
+    pub webhook_url: Option<Secret>,
+    /// Bot token for the Web API, e.g. `xoxb-...`, needs the `chat:write` and
+    /// `chat:write.customize` scopes
+    pub bot_token: Option<Secret>,
+    #[serde(default = "default_slack_api_url")]
+    pub api_url: String,
+    /// What to do with the original alert when it's resolved, only with a bot token
+    #[serde(default)]
+    pub on_resolve: SlackResolveMode,
     pub channel: String,
     pub screen_name: String,
     pub icon_url: Option<String>,
     pub icon_emoji: Option<String>,
+    /// Prepended to warning alerts, e.g. `<!here>` or `<@U024BE7LH>`
+    pub warning_mention: Option<String>,
+    /// Prepended to critical alerts, e.g. `<!channel>`
+    pub critical_mention: Option<String>,
+    #[serde(default)]
+    pub format: SlackFormat,
+    /// Text of the alerts with `{placeholders}`, a trigger's template takes precedence
+    pub message_template: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+fn default_slack_api_url() -> String {
+    "https://slack.com/api".into()
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SlackResolveMode {
+    /// Reply to the alert in a thread
+    #[default]
+    Thread,
+    /// Replace the alert with the recovery
+    Update,
+    /// Send the recovery as a separate message
+    Message,
+}
+
+/// `text` sends a single line of text, `blocks` adds a Block Kit layout
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SlackFormat {
+    #[default]
+    Text,
+    Blocks,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WebhookConfig {
+    /// Used in logs to tell the webhooks apart
+    #[serde(default = "default_webhook_name")]
+    pub name: String,
+    pub url: Secret,
+    #[serde(default)]
+    pub headers: HashMap<String, Secret>,
+    /// JSON body with `{placeholders}` for the alert values, all values are
+    /// sent as a JSON object if not set
+    pub body_template: Option<String>,
+    /// Status codes counted as success, any 2xx if empty
+    #[serde(default)]
+    pub expected_status: Vec<u16>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+fn default_webhook_name() -> String {
+    "webhook".into()
+}
+
+/// Where the alerts of the triggers referring to the route are delivered
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RouteConfig {
+    /// Names of the notifiers to use, `slack` or the name of a webhook. All
+    /// notifiers are used if empty.
+    #[serde(default)]
+    pub notifiers: Vec<String>,
+    /// Used instead of the channel in the Slack config
+    pub slack_channel: Option<String>,
+    /// Used instead of the webhook in the Slack config
+    pub slack_webhook_url: Option<Secret>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Trigger {
     ConsumersTotal(TriggerData),
@@ -118,31 +435,338 @@ impl Trigger {
     }
 }
 
+/// Fires when the RabbitMQ management API can't be polled, either after a
+/// number of consecutive failed polls or after some time without any data
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct BrokerUnreachableTrigger {
+    pub failed_polls: Option<u32>,
+    pub no_data_seconds: Option<u64>,
+    pub route: Option<String>,
+    pub message_template: Option<String>,
+    pub runbook_url: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TriggerData {
-    pub threshold: f64,
+    pub warning: Option<f64>,
+
+    /// `threshold` is the same as `critical`
+    #[serde(alias = "threshold")]
+    pub critical: Option<f64>,
 
     #[serde(default = "default_trigger_when")]
     pub trigger_when: TriggerWhen,
 
+    /// How long the threshold has to be passed before alerting
+    pub for_seconds: Option<u64>,
+
+    /// The value has to cross back past this one for a breach to end
+    pub clear_threshold: Option<f64>,
+
     pub queue: Option<String>,
+
+    /// Glob pattern the queue name has to match, e.g. `billing.tenant-*`
+    #[serde(default, deserialize_with = "deserialize_glob")]
+    pub queue_pattern: Option<Pattern>,
+
+    /// Regular expression the queue name has to match
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub queue_regex: Option<Regex>,
+
+    /// Glob patterns of queue names the trigger never applies to
+    #[serde(default, deserialize_with = "deserialize_globs")]
+    pub exclude: Vec<Pattern>,
+
+    pub vhost: Option<String>,
+
+    /// Name of the route the alerts are delivered through, all notifiers with
+    /// their default settings if not set
+    pub route: Option<String>,
+
+    /// Text of the alert with `{placeholders}`, overrides the notifier's template
+    pub message_template: Option<String>,
+
+    /// Link to the instructions on handling the alert
+    pub runbook_url: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+impl TriggerData {
+    /// The threshold at which the trigger starts firing, the warning one if set
+    pub fn threshold(&self) -> f64 {
+        self.warning
+            .or(self.critical)
+            .expect("Trigger without a threshold, should've been checked when reading the config")
+    }
+
+    pub fn severity_threshold(&self, severity: Severity) -> f64 {
+        match severity {
+            Severity::Warning => self.warning,
+            Severity::Critical => self.critical,
+        }
+        .unwrap_or_else(|| self.threshold())
+    }
+}
+
+/// Compared by hand since `Regex` can't be compared, two regexes are the same
+/// if their patterns are
+impl PartialEq for TriggerData {
+    fn eq(&self, other: &Self) -> bool {
+        let TriggerData {
+            warning,
+            critical,
+            trigger_when,
+            for_seconds,
+            clear_threshold,
+            queue,
+            queue_pattern,
+            queue_regex,
+            exclude,
+            vhost,
+            route,
+            message_template,
+            runbook_url,
+        } = self;
+
+        *warning == other.warning
+            && *critical == other.critical
+            && *trigger_when == other.trigger_when
+            && *for_seconds == other.for_seconds
+            && *clear_threshold == other.clear_threshold
+            && *queue == other.queue
+            && *queue_pattern == other.queue_pattern
+            && queue_regex.as_ref().map(Regex::as_str)
+                == other.queue_regex.as_ref().map(Regex::as_str)
+            && *exclude == other.exclude
+            && *vhost == other.vhost
+            && *route == other.route
+            && *message_template == other.message_template
+            && *runbook_url == other.runbook_url
+    }
+}
+
+fn deserialize_glob<'de, D>(deserializer: D) -> Result<Option<Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|pattern| Pattern::new(&pattern).map_err(D::Error::custom))
+        .transpose()
+}
+
+fn deserialize_globs<'de, D>(deserializer: D) -> Result<Vec<Pattern>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| Pattern::new(pattern).map_err(D::Error::custom))
+        .collect()
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|regex| Regex::new(&regex).map_err(D::Error::custom))
+        .transpose()
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum TriggerWhen {
     Above,
     Below,
 }
 
+impl TriggerWhen {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TriggerWhen::Above => "above",
+            TriggerWhen::Below => "below",
+        }
+    }
+
+    /// Whether the value is past the threshold in the direction of the trigger
+    pub fn passes(&self, value: f64, threshold: f64) -> bool {
+        match *self {
+            TriggerWhen::Above => value > threshold,
+            TriggerWhen::Below => value < threshold,
+        }
+    }
+
+    /// Describes the value crossing the threshold, e.g. `rose above`
+    pub fn crossed(&self) -> &'static str {
+        match *self {
+            TriggerWhen::Above => "rose above",
+            TriggerWhen::Below => "dropped below",
+        }
+    }
+}
+
 fn default_trigger_when() -> TriggerWhen {
     TriggerWhen::Above
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
 pub fn read_config(path: &PathBuf) -> Result<Config> {
     let config_contents: String = read_to_string(path)
         .with_context(|| format!("Could not read config {}", path.as_path().display()))?;
 
-    let config: Config = toml::from_str(&config_contents).context("Could not parse TOML config")?;
+    parse_config(&config_contents)
+}
+
+/// Parses and validates the contents of a config file
+pub fn parse_config(config_contents: &str) -> Result<Config> {
+    let mut config: Config =
+        toml::from_str(config_contents).context("Could not parse TOML config")?;
+
+    if let Some(mut rabbitmq) = config.rabbitmq.take() {
+        rabbitmq.normalize().context("Invalid [rabbitmq] section")?;
+        config.brokers.insert(
+            0,
+            BrokerConfig {
+                name: rabbitmq.host.clone(),
+                rabbitmq,
+                triggers: None,
+            },
+        );
+    }
+    if config.brokers.is_empty() {
+        bail!("Config needs either a [rabbitmq] section or [[brokers]]");
+    }
+    for broker in &mut config.brokers {
+        broker
+            .rabbitmq
+            .normalize()
+            .with_context(|| format!("Invalid broker {}", &broker.name))?;
+    }
+    if let Some(broker) = config
+        .brokers
+        .iter()
+        .enumerate()
+        .find_map(|(index, broker)| {
+            config.brokers[..index]
+                .iter()
+                .any(|other| other.name == broker.name)
+                .then_some(broker)
+        })
+    {
+        bail!("There's more than one broker named {}", broker.name);
+    }
+
+    if let Some(trigger) = config
+        .all_triggers()
+        .find(|trigger| trigger.data().warning.is_none() && trigger.data().critical.is_none())
+    {
+        bail!(
+            "Trigger for {} needs a threshold, a warning or a critical value",
+            trigger.name()
+        );
+    }
+
+    if let Some(trigger) = config.all_triggers().find(|trigger| {
+        let data = trigger.data();
+        match (data.warning, data.critical) {
+            (Some(warning), Some(critical)) => data.trigger_when.passes(warning, critical),
+            _ => false,
+        }
+    }) {
+        let data = trigger.data();
+        bail!(
+            "Trigger for {} has a warning threshold of {} which is {} its critical threshold of {}, the warning has to be passed first",
+            trigger.name(),
+            data.warning.unwrap_or_default(),
+            data.trigger_when.name(),
+            data.critical.unwrap_or_default()
+        );
+    }
+
+    if let Some(trigger) = config.all_triggers().find(|trigger| {
+        let data = trigger.data();
+        data.clear_threshold.is_some_and(|clear_threshold| {
+            data.trigger_when.passes(clear_threshold, data.threshold())
+        })
+    }) {
+        let data = trigger.data();
+        bail!(
+            "Trigger for {} has a clear_threshold of {} which is {} its threshold of {}, breaches would end right away",
+            trigger.name(),
+            data.clear_threshold.unwrap_or_default(),
+            data.trigger_when.name(),
+            data.threshold()
+        );
+    }
+
+    if let Some(slack_config) = &config.slack {
+        if slack_config.webhook_url.is_none() && slack_config.bot_token.is_none() {
+            bail!("Slack config needs either a webhook_url or a bot_token");
+        }
+    }
+
+    let trigger_routes = config
+        .all_triggers()
+        .filter_map(|trigger| trigger.data().route.as_ref());
+    let broker_unreachable_route = config
+        .broker_unreachable
+        .as_ref()
+        .and_then(|trigger| trigger.route.as_ref());
+    if let Some(route) = trigger_routes
+        .chain(broker_unreachable_route)
+        .find(|route| !config.routes.contains_key(*route))
+    {
+        bail!("Route {} is used by a trigger but isn't configured", route);
+    }
+
+    for (route_name, route) in &config.routes {
+        if let Some(notifier) = route.notifiers.iter().find(|notifier| {
+            !(notifier.as_str() == "slack" && config.slack.is_some()
+                || config
+                    .webhooks
+                    .iter()
+                    .any(|webhook| &webhook.name == *notifier))
+        }) {
+            bail!(
+                "Route {} uses the notifier {} which isn't configured",
+                route_name,
+                notifier
+            );
+        }
+    }
+
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vhosts_names() {
+        assert_eq!(Vhosts::default().names(), None);
+        assert_eq!(Vhosts::Single("/".into()).names(), Some(vec!["/"]));
+        assert_eq!(
+            Vhosts::List(vec!["/".into(), "billing".into()]).names(),
+            Some(vec!["/", "billing"])
+        );
+        assert_eq!(
+            Vhosts::List(vec!["billing".into(), "all".into()]).names(),
+            None
+        );
+    }
+}
@@ -1,27 +1,36 @@
+mod alert;
+mod check;
 mod config;
+mod metrics;
+mod monitor;
+mod notifier;
+mod reload;
 mod rmq;
+mod server;
 mod slack;
+mod state;
+mod template;
 mod utils;
+mod webhook;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use async_std::stream;
 use async_std::task;
-use futures::{
-    future::FutureExt,
-    stream::{futures_unordered::FuturesUnordered, StreamExt},
-};
+use futures::stream::StreamExt;
 use human_panic::setup_panic;
-use smol_str::SmolStr;
-use std::{collections::HashMap, path::PathBuf, sync::Arc, thread, time::Duration};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use structopt::StructOpt;
 
-use config::{read_config, RabbitMqConfig, SlackConfig, Trigger};
-use rmq::get_queue_info;
-use slack::send_slack_msg;
-use utils::{
-    build_msgs_for_trigger, get_unix_timestamp, has_msg_expired, ExpirationStatus,
-    MsgExpirationLog, QueueName, TriggerType,
-};
+use config::{read_config, Config};
+use metrics::Metrics;
+use monitor::Monitor;
+use notifier::{build_notifiers, Notifiers};
+use reload::ConfigWatcher;
+use slack::PostedMsgs;
 
 #[derive(Debug, StructOpt)]
 struct Cli {
@@ -32,6 +41,28 @@ struct Cli {
     /// Print debug logs
     #[structopt(short = "v", long = "verbose")]
     verbose: bool,
+
+    /// Check the queues once and exit, with exit code 2 if any trigger is firing
+    #[structopt(long = "once")]
+    once: bool,
+
+    /// Print the alerts to stdout instead of sending them, as JSON with --once
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Validates the config and exits with a non-zero code if there are problems
+    CheckConfig {
+        /// Also checks the secrets, the TLS files and that the queues the
+        /// triggers refer to exist on the broker
+        #[structopt(long = "online")]
+        online: bool,
+    },
 }
 
 fn main() -> Result<()> {
@@ -46,7 +77,12 @@ fn main() -> Result<()> {
     let log_env = env_logger::Env::default().default_filter_or(log_filter);
     env_logger::Builder::from_env(log_env).init();
 
-    let config = read_config(&args.config_path)?;
+    if let Some(Command::CheckConfig { online }) = args.command {
+        let config = read_config(&args.config_path)?;
+        return task::block_on(check_config_command(&args.config_path, &config, online));
+    }
+
+    let config = read_checked_config(&args.config_path)?;
 
     log::info!(
         "Read config file from {}. Checking queue info every {}s.",
@@ -54,111 +90,163 @@ fn main() -> Result<()> {
         &config.settings.poll_seconds,
     );
     log::debug!("Config loaded: {:?}", config);
+    if args.dry_run {
+        log::info!("Dry run, alerts are printed instead of sent");
+    }
+
+    let posted_msgs = PostedMsgs::default();
+    let notifiers = if args.once && args.dry_run {
+        Notifiers::json()
+    } else {
+        build_notifiers(&config, args.dry_run, &posted_msgs)
+    };
+    let metrics = Arc::new(Metrics::default());
+    metrics.register_notifiers(notifiers.names());
 
-    let sleep_time = Duration::from_secs(config.settings.poll_seconds);
-    task::block_on(check_loop(
-        sleep_time,
-        config.settings.msg_expiration_seconds,
-        config.rabbitmq,
-        config.slack,
-        config.triggers,
-    ))
+    let server_config = config.server.clone();
+    let poll_seconds = config.settings.poll_seconds;
+    let mut monitor = Monitor::new(config, notifiers, Arc::clone(&metrics), args.dry_run)?;
+
+    if args.once {
+        return task::block_on(check_once(&mut monitor));
+    }
+
+    let server =
+        server_config.map(|server_config| server::serve(server_config, poll_seconds, metrics));
+    let config_watcher = ConfigWatcher::new(&args.config_path)?;
+    let check_loop = check_loop(monitor, config_watcher, args.dry_run, posted_msgs);
+
+    task::block_on(async {
+        match server {
+            Some(server) => futures::try_join!(check_loop, server).map(|_| ()),
+            None => check_loop.await,
+        }
+    })
 }
 
-pub async fn check_loop(
-    poll_interval: Duration,
-    expiration_in_seconds: u64,
-    rmq_config: RabbitMqConfig,
-    slack_config: SlackConfig,
-    triggers: Vec<Trigger>,
-) -> Result<()> {
-    let mut interval = stream::interval(poll_interval);
+async fn check_config_command(config_path: &Path, config: &Config, online: bool) -> Result<()> {
+    let mut problems = check::check_config(config);
+    if online {
+        let environment_problems = check::check_environment(config);
+        if environment_problems.is_empty() {
+            problems.extend(check::check_queues_exist(config).await?);
+        }
+        problems.extend(environment_problems);
+    }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        bail!(
+            "Found {} problems in the config {}",
+            problems.len(),
+            config_path.display()
+        );
+    }
 
-    let mut sent_msgs_registry: MsgExpirationLog = HashMap::new();
+    println!("Config {} is valid", config_path.display());
+    Ok(())
+}
 
-    while interval.next().await.is_some() {
-        log::info!(
-            "Checking queue info at {}://{}:{}",
-            &rmq_config.protocol,
-            &rmq_config.host,
-            &rmq_config.port
+/// Checks the queues once, exits with code 2 if any trigger is firing,
+/// whether its alert was sent now or by an earlier run
+async fn check_once(monitor: &mut Monitor) -> Result<()> {
+    let firing_alerts = monitor.check().await?;
+    let failed_brokers = monitor.failed_brokers();
+    if !failed_brokers.is_empty() {
+        bail!(
+            "Could not fetch queue info from {}",
+            failed_brokers.join(", ")
         );
+    }
 
-        let queue_info = get_queue_info(
-            &rmq_config.protocol,
-            &rmq_config.host,
-            &rmq_config.port,
-            &rmq_config.username,
-            &rmq_config.password,
-        )
-        .await?;
-
-        log::debug!("Fetched queue info: {:?}", queue_info);
-
-        triggers
-            .iter()
-            .flat_map(|trigger| build_msgs_for_trigger(&queue_info, trigger, &slack_config))
-            .filter_map(|msg| {
-                let queue_trigger_type: (QueueName, TriggerType) = (SmolStr::new(&msg.metadata.queue_name), SmolStr::new(&msg.metadata.trigger_type));
-                let current_ts = get_unix_timestamp().ok()?;
-                match has_msg_expired(
-                    &mut sent_msgs_registry,
-                    queue_trigger_type,
-                    current_ts,
-                    expiration_in_seconds,
-                ) {
-                    Ok(ExpirationStatus::Expired) => {
-                        log::debug!(
-                            "Message for queue {} of type {} has expired (expiration time is {}s). Resending...",
-                            &msg.metadata.queue_name,
-                            &msg.metadata.trigger_type,
-                            &expiration_in_seconds,
-                        );
-                        Some(msg)
-                    }
-                    Ok(ExpirationStatus::NotExpired) => {
-                        log::debug!(
-                            "Last message for queue {} of type {} was sent too recently. Skipping sending this one...",
-                            &msg.metadata.queue_name,
-                            &msg.metadata.trigger_type
-                        );
-                        None
-                    }
-                    Ok(ExpirationStatus::NotSentYet) => {
-                        log::debug!(
-                            "Haven't yet sent a message for queue {} of type {} has expired. Saved in log.",
-                            &msg.metadata.queue_name,
-                            &msg.metadata.trigger_type
-                        );
-                        Some(msg)
+    if !firing_alerts.is_empty() {
+        log::info!("{} alerts are firing", firing_alerts.len());
+        std::process::exit(2);
+    }
+
+    log::info!("No alerts are firing");
+    Ok(())
+}
+
+/// How often the config file is checked for changes
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Tick {
+    Poll,
+    CheckConfig,
+}
+
+/// Reads and validates the config again and applies it to the monitor. An
+/// invalid config, or one which can't be applied, is rejected and the old one kept.
+/// Reads the config and runs the same checks as `check-config --online`,
+/// apart from the ones which need the broker
+fn read_checked_config(config_path: &PathBuf) -> Result<Config> {
+    let config = read_config(config_path)?;
+    let mut problems = check::check_config(&config);
+    problems.extend(check::check_environment(&config));
+    if !problems.is_empty() {
+        bail!("Invalid config: {}", problems.join(", "));
+    }
+    Ok(config)
+}
+
+fn reload_config(
+    monitor: &mut Monitor,
+    config_path: &PathBuf,
+    dry_run: bool,
+    posted_msgs: &PostedMsgs,
+) {
+    log::info!("Reloading the config from {}", config_path.display());
+
+    let config = match read_checked_config(config_path) {
+        Ok(config) => config,
+        Err(error) => {
+            log::error!("Keeping the old config: {:#}", error);
+            return;
+        }
+    };
+
+    let notifiers = build_notifiers(&config, dry_run, posted_msgs);
+    match monitor.apply_config(config, notifiers) {
+        Ok(()) => log::info!("Config reloaded"),
+        Err(error) => log::error!(
+            "Could not apply the config, keeping the old one: {:#}",
+            error
+        ),
+    }
+}
+
+pub async fn check_loop(
+    mut monitor: Monitor,
+    mut config_watcher: ConfigWatcher,
+    dry_run: bool,
+    posted_msgs: PostedMsgs,
+) -> Result<()> {
+    loop {
+        let poll_interval = monitor.poll_interval();
+        let mut ticks = futures::stream::select(
+            stream::interval(poll_interval).map(|_| Tick::Poll),
+            stream::interval(CONFIG_CHECK_INTERVAL).map(|_| Tick::CheckConfig),
+        );
+
+        while let Some(tick) = ticks.next().await {
+            match tick {
+                Tick::Poll => {
+                    monitor.check().await?;
+                    if monitor.failed_brokers().is_empty() {
+                        log::info!("Check passed, sleeping for {}s", &poll_interval.as_secs());
                     }
-                    Err(error) => {
-                        log::error!("Unexpected error with msgs: {}", error);
-                        None
+                }
+                Tick::CheckConfig if config_watcher.should_reload() => {
+                    reload_config(&mut monitor, config_watcher.path(), dry_run, &posted_msgs);
+                    if monitor.poll_interval() != poll_interval {
+                        break;
                     }
                 }
-            })
-            .map(|msg| {
-                let msg = Arc::new(msg);
-                send_slack_msg(&slack_config.webhook_url, Arc::clone(&msg))
-                    .map(move |x| {
-                            match x {
-                            Ok(_) => {
-                                log::info!("Sent message to {} about {}", msg.channel, msg.metadata.queue_name);
-                                log::debug!(
-                                    "Slack message body {:?}, sent on {:?}",
-                                    msg,
-                                    thread::current().id()
-                                );
-                            }
-                            Err(e) => log::error!("Error sending Slack message: {}", e),
-                        };
-                    })
-            })
-            .collect::<FuturesUnordered<_>>()
-            .collect::<Vec<()>>().await;
-
-        log::info!("Check passed, sleeping for {}s", &poll_interval.as_secs(),);
+                Tick::CheckConfig => {}
+            }
+        }
     }
-    Ok(())
 }
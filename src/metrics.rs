@@ -0,0 +1,338 @@
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::rmq::QueueInfo;
+use crate::utils::UnixTimestamp;
+
+/// Values exported on the `/metrics` and health endpoints, updated by the check loop
+#[derive(Default)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+}
+
+#[derive(Default)]
+struct MetricsState {
+    /// The polls by broker name
+    brokers: BTreeMap<String, BrokerState>,
+    /// Successfully sent alerts by notifier name
+    alerts_sent: BTreeMap<String, u64>,
+    /// Failed notifications by notifier name
+    notifier_errors: BTreeMap<String, u64>,
+    /// The outcome of the last notification by notifier name
+    notifier_status: BTreeMap<String, NotifierStatus>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    queue_info: Vec<QueueInfo>,
+    last_poll_duration: Option<Duration>,
+    last_poll_at: Option<UnixTimestamp>,
+    last_success_at: Option<UnixTimestamp>,
+    last_poll_succeeded: Option<bool>,
+    polls: u64,
+    poll_failures: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct NotifierStatus {
+    /// Whether the last alert was delivered, `None` if none was sent yet
+    pub reachable: Option<bool>,
+    pub last_error: Option<String>,
+    /// When the last alert failed to send, if it did
+    pub last_error_at: Option<UnixTimestamp>,
+}
+
+/// The body of the health endpoints
+#[derive(Serialize, Debug)]
+pub struct Health {
+    /// The check loop has polled every broker recently, successfully or not
+    pub healthy: bool,
+    /// The last poll of every broker succeeded recently and no notifier
+    /// failed its last alert recently
+    pub ready: bool,
+    pub brokers: BTreeMap<String, BrokerHealth>,
+    pub notifiers: BTreeMap<String, NotifierStatus>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BrokerHealth {
+    pub healthy: bool,
+    pub ready: bool,
+    pub last_poll_success: Option<bool>,
+    pub last_poll_at: Option<UnixTimestamp>,
+    pub last_success_at: Option<UnixTimestamp>,
+    pub last_success_age_seconds: Option<u64>,
+}
+
+impl Metrics {
+    fn state(&self) -> std::sync::MutexGuard<'_, MetricsState> {
+        self.state.lock().expect("Metrics lock poisoned")
+    }
+
+    /// Adds the notifiers to the health status before they've sent anything
+    pub fn register_notifiers<'a>(&self, names: impl IntoIterator<Item = &'a str>) {
+        let mut state = self.state();
+        for name in names {
+            state.notifier_status.entry(name.into()).or_default();
+        }
+    }
+
+    /// Sets the brokers to report on, the ones which aren't monitored
+    /// anymore are dropped
+    pub fn register_brokers<'a>(&self, names: impl IntoIterator<Item = &'a str>) {
+        let names: Vec<&str> = names.into_iter().collect();
+        let mut state = self.state();
+        state
+            .brokers
+            .retain(|name, _| names.contains(&name.as_str()));
+        for name in names {
+            state.brokers.entry(name.into()).or_default();
+        }
+    }
+
+    /// Records a poll of the RabbitMQ API of a broker, the queue info is
+    /// kept until the next successful poll
+    pub fn record_poll(
+        &self,
+        broker: &str,
+        duration: Duration,
+        current_ts: UnixTimestamp,
+        queue_info: Option<&[QueueInfo]>,
+    ) {
+        let mut state = self.state();
+        let state = state.brokers.entry(broker.into()).or_default();
+        state.polls += 1;
+        state.last_poll_duration = Some(duration);
+        state.last_poll_at = Some(current_ts);
+        state.last_poll_succeeded = Some(queue_info.is_some());
+        match queue_info {
+            Some(queue_info) => {
+                state.queue_info = queue_info.to_vec();
+                state.last_success_at = Some(current_ts);
+            }
+            None => state.poll_failures += 1,
+        }
+    }
+
+    pub fn record_notification(
+        &self,
+        notifier: &str,
+        current_ts: UnixTimestamp,
+        error: Option<&anyhow::Error>,
+    ) {
+        let mut state = self.state();
+        let counter = match error {
+            None => &mut state.alerts_sent,
+            Some(_) => &mut state.notifier_errors,
+        };
+        *counter.entry(notifier.into()).or_default() += 1;
+        state.notifier_status.insert(
+            notifier.into(),
+            NotifierStatus {
+                reachable: Some(error.is_none()),
+                last_error: error.map(|error| format!("{:#}", error)),
+                last_error_at: error.map(|_| current_ts),
+            },
+        );
+    }
+
+    /// The health of the monitor. Polls older than `max_poll_age` count as
+    /// missing, before the first poll the monitor is healthy but not ready.
+    /// A notifier which failed longer ago than that doesn't affect readiness,
+    /// it might not have had another alert to send since.
+    pub fn health(
+        &self,
+        current_ts: UnixTimestamp,
+        max_poll_age: u64,
+        started_at: UnixTimestamp,
+    ) -> Health {
+        let state = self.state();
+        let is_recent = |ts: UnixTimestamp| current_ts.saturating_sub(ts) <= max_poll_age;
+
+        let brokers: BTreeMap<String, BrokerHealth> = state
+            .brokers
+            .iter()
+            .map(|(name, broker)| {
+                let health = BrokerHealth {
+                    healthy: is_recent(broker.last_poll_at.unwrap_or(started_at)),
+                    ready: broker.last_poll_succeeded == Some(true)
+                        && broker.last_success_at.is_some_and(is_recent),
+                    last_poll_success: broker.last_poll_succeeded,
+                    last_poll_at: broker.last_poll_at,
+                    last_success_at: broker.last_success_at,
+                    last_success_age_seconds: broker
+                        .last_success_at
+                        .map(|ts| current_ts.saturating_sub(ts)),
+                };
+                (name.clone(), health)
+            })
+            .collect();
+        let notifiers_reachable = state
+            .notifier_status
+            .values()
+            .all(|status| !status.last_error_at.is_some_and(is_recent));
+
+        Health {
+            healthy: brokers.values().all(|broker| broker.healthy),
+            ready: !brokers.is_empty()
+                && brokers.values().all(|broker| broker.ready)
+                && notifiers_reachable,
+            brokers,
+            notifiers: state.notifier_status.clone(),
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let state = self.state();
+        let mut output = String::new();
+
+        let mut queue_stats: QueueStatsByMetric = BTreeMap::new();
+        for (broker, broker_state) in &state.brokers {
+            for qi in &broker_state.queue_info {
+                let (name, help) = qi.stat.stat_type.metric();
+                queue_stats
+                    .entry(name)
+                    .or_insert((help, Vec::new()))
+                    .1
+                    .push((broker, qi));
+            }
+        }
+        for (name, (help, queue_info)) in queue_stats {
+            write_header(&mut output, name, help, "gauge");
+            for (broker, qi) in queue_info {
+                let _ = writeln!(
+                    output,
+                    "{}{{broker=\"{}\",vhost=\"{}\",queue=\"{}\"}} {}",
+                    name,
+                    escape_label(broker),
+                    escape_label(&qi.vhost),
+                    escape_label(&qi.name),
+                    qi.stat.value
+                );
+            }
+        }
+
+        write_header(
+            &mut output,
+            "rmq_monitor_poll_duration_seconds",
+            "Duration of the last poll of the RabbitMQ API, retries included",
+            "gauge",
+        );
+        for (broker, broker_state) in &state.brokers {
+            if let Some(duration) = broker_state.last_poll_duration {
+                let _ = writeln!(
+                    output,
+                    "rmq_monitor_poll_duration_seconds{{broker=\"{}\"}} {}",
+                    escape_label(broker),
+                    duration.as_secs_f64()
+                );
+            }
+        }
+
+        write_counters_by_label(
+            &mut output,
+            "rmq_monitor_polls_total",
+            "Polls of the RabbitMQ API",
+            "broker",
+            state
+                .brokers
+                .iter()
+                .map(|(broker, broker_state)| (broker, broker_state.polls)),
+        );
+        write_counters_by_label(
+            &mut output,
+            "rmq_monitor_poll_failures_total",
+            "Polls of the RabbitMQ API which failed after all retries",
+            "broker",
+            state
+                .brokers
+                .iter()
+                .map(|(broker, broker_state)| (broker, broker_state.poll_failures)),
+        );
+
+        write_counters_by_label(
+            &mut output,
+            "rmq_monitor_alerts_sent_total",
+            "Alerts sent successfully",
+            "notifier",
+            state
+                .alerts_sent
+                .iter()
+                .map(|(notifier, count)| (notifier, *count)),
+        );
+        write_counters_by_label(
+            &mut output,
+            "rmq_monitor_notifier_errors_total",
+            "Alerts which failed to send",
+            "notifier",
+            state
+                .notifier_errors
+                .iter()
+                .map(|(notifier, count)| (notifier, *count)),
+        );
+
+        output
+    }
+}
+
+/// The help text and the queue info with its broker for each metric name
+type QueueStatsByMetric<'a> = BTreeMap<&'a str, (&'a str, Vec<(&'a str, &'a QueueInfo)>)>;
+
+fn write_header(output: &mut String, name: &str, help: &str, metric_type: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+}
+
+fn write_counters_by_label<'a>(
+    output: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    counters: impl Iterator<Item = (&'a String, u64)>,
+) {
+    write_header(output, name, help, "counter");
+    for (label_value, count) in counters {
+        let _ = writeln!(
+            output,
+            "{}{{{}=\"{}\"}} {}",
+            name,
+            label,
+            escape_label(label_value),
+            count
+        );
+    }
+}
+
+/// Escapes a label value, backslashes, double quotes and line feeds have to be escaped
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifier_failure_expires_from_readiness() {
+        let metrics = Metrics::default();
+        metrics.register_brokers(["main"]);
+        metrics.record_poll("main", Duration::from_millis(10), 1000, Some(&[]));
+        metrics.record_notification("slack", 1000, Some(&anyhow::anyhow!("HTTP 500")));
+
+        let health = metrics.health(1100, 300, 0);
+        assert!(!health.ready);
+        assert_eq!(health.notifiers["slack"].reachable, Some(false));
+
+        metrics.record_poll("main", Duration::from_millis(10), 1400, Some(&[]));
+        let health = metrics.health(1400, 300, 0);
+        assert!(health.ready);
+        assert_eq!(health.notifiers["slack"].reachable, Some(false));
+    }
+}
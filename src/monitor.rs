@@ -0,0 +1,857 @@
+use anyhow::Result;
+use futures::{
+    future::FutureExt,
+    stream::{futures_unordered::FuturesUnordered, StreamExt},
+};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::alert::{Alert, BROKER_UNREACHABLE_TRIGGER_TYPE};
+use crate::config::{BrokerConfig, Config, Failover, RetryPolicy, Trigger};
+use crate::metrics::Metrics;
+use crate::notifier::Notifiers;
+use crate::rmq::{get_queue_info_with_failover, http_client, QueueInfo};
+use crate::state::{load_state, save_state};
+use crate::utils::{
+    alert_key, build_broker_unreachable_alert, build_resolved_alert, evaluate_triggers,
+    get_unix_timestamp, has_msg_expired, record_breach, resolve_breaches, trigger_matches_queue,
+    with_retries, AlertKey, ExpirationStatus, MsgExpirationLog, PollHistory, UnixTimestamp,
+};
+
+/// Polls the brokers and alerts about the triggers, keeping the state of the
+/// alerts between checks
+pub struct Monitor {
+    config: Config,
+    notifiers: Notifiers,
+    metrics: Arc<Metrics>,
+    sent_msgs_registry: MsgExpirationLog,
+    /// The polls of each broker by name
+    brokers: HashMap<String, BrokerState>,
+    /// The state file is neither read nor written in a dry run, so it
+    /// doesn't keep a real instance from alerting
+    dry_run: bool,
+}
+
+struct BrokerState {
+    /// The client for the management API, with the TLS options of the broker
+    client: surf::Client,
+    poll_history: PollHistory,
+    /// When the broker started being monitored, used instead of the last
+    /// successful poll until there's one
+    started_at: UnixTimestamp,
+    /// The index of the node in `hosts` which is polled first
+    node: usize,
+}
+
+impl BrokerState {
+    fn new(client: surf::Client, started_at: UnixTimestamp) -> Self {
+        BrokerState {
+            client,
+            poll_history: PollHistory::default(),
+            started_at,
+            node: 0,
+        }
+    }
+
+    /// The node to poll first, the first one if the hosts changed since
+    fn first_node(&self, broker: &BrokerConfig) -> usize {
+        if self.node < broker.rabbitmq.hosts.len() {
+            self.node
+        } else {
+            0
+        }
+    }
+
+    /// Remembers which node answered and picks the one to poll first next time
+    fn record_node(&mut self, broker: &BrokerConfig, node: usize) {
+        let rmq_config = &broker.rabbitmq;
+        let first_node = self.first_node(broker);
+        if node != first_node {
+            log::warn!(
+                "Failed over from node {} to {} of {}",
+                rmq_config.node_address(&rmq_config.hosts[first_node]),
+                rmq_config.node_address(&rmq_config.hosts[node]),
+                &broker.name
+            );
+        }
+
+        self.node = match rmq_config.failover {
+            Failover::InOrder => node,
+            Failover::RoundRobin => (node + 1) % rmq_config.hosts.len(),
+        };
+    }
+}
+
+/// The triggers which could have raised alerts with the given key
+fn triggers_for_key<'a>(config: &'a Config, key: &AlertKey) -> Vec<&'a Trigger> {
+    let (broker_name, vhost, queue_name, trigger_type, route) = key;
+    let triggers = config
+        .brokers
+        .iter()
+        .find(|broker| broker.name == broker_name.as_str())
+        .map_or(&[][..], |broker| config.broker_triggers(broker));
+
+    triggers
+        .iter()
+        .filter(|trigger| {
+            trigger.name() == trigger_type
+                && trigger.data().route.as_deref().unwrap_or_default() == route
+                && trigger_matches_queue(trigger, vhost, queue_name)
+        })
+        .collect()
+}
+
+/// Fetches the queue info of the broker with retries, starting with the
+/// given node. Returns how long it took as well.
+async fn poll_broker(
+    broker: &BrokerConfig,
+    client: surf::Client,
+    retry_policy: &RetryPolicy,
+    first_node: usize,
+) -> (Duration, Result<(usize, Vec<QueueInfo>)>) {
+    log::info!(
+        "Checking queue info of {} at {}",
+        &broker.name,
+        broker
+            .rabbitmq
+            .node_address(&broker.rabbitmq.hosts[first_node])
+    );
+
+    let poll_started_at = Instant::now();
+    let result = with_retries(retry_policy, || {
+        get_queue_info_with_failover(&client, &broker.rabbitmq, first_node)
+    })
+    .await;
+    (poll_started_at.elapsed(), result)
+}
+
+impl Monitor {
+    pub fn new(
+        config: Config,
+        notifiers: Notifiers,
+        metrics: Arc<Metrics>,
+        dry_run: bool,
+    ) -> Result<Self> {
+        let state_file = config.settings.state_file.as_ref().filter(|_| !dry_run);
+        let sent_msgs_registry = match state_file {
+            Some(state_file) => match load_state(state_file) {
+                Ok(sent_msgs_registry) => {
+                    log::info!(
+                        "Loaded the state of {} alerts from {}",
+                        sent_msgs_registry.len(),
+                        state_file.display()
+                    );
+                    sent_msgs_registry
+                }
+                Err(error) => {
+                    log::error!(
+                        "Starting without the saved state of the alerts: {:#}",
+                        error
+                    );
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let started_at = get_unix_timestamp()?;
+        let request_timeout = Duration::from_secs(config.settings.request_timeout_seconds);
+        let brokers = config
+            .brokers
+            .iter()
+            .map(|broker| {
+                let client = http_client(&broker.rabbitmq, request_timeout)?;
+                Ok((broker.name.clone(), BrokerState::new(client, started_at)))
+            })
+            .collect::<Result<_>>()?;
+        metrics.register_brokers(config.brokers.iter().map(|broker| broker.name.as_str()));
+
+        Ok(Monitor {
+            config,
+            notifiers,
+            metrics,
+            sent_msgs_registry,
+            brokers,
+            dry_run,
+        })
+    }
+
+    fn state_file(&self) -> Option<&Path> {
+        match &self.config.settings.state_file {
+            Some(state_file) if !self.dry_run => Some(state_file),
+            _ => None,
+        }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.config.settings.poll_seconds)
+    }
+
+    /// Switches to a new config. The state of the alerts is kept for the
+    /// queues whose triggers haven't changed, all of it is dropped for the
+    /// brokers which were removed or whose address changed. If the clients
+    /// for the brokers can't be built the old config is kept as it is.
+    pub fn apply_config(&mut self, config: Config, notifiers: Notifiers) -> Result<()> {
+        let started_at = get_unix_timestamp()?;
+        let request_timeout = Duration::from_secs(config.settings.request_timeout_seconds);
+        let clients = config
+            .brokers
+            .iter()
+            .map(|broker| {
+                let client = http_client(&broker.rabbitmq, request_timeout)?;
+                Ok((broker.name.clone(), client))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        for old_broker in &self.config.brokers {
+            let new_broker = config
+                .brokers
+                .iter()
+                .find(|broker| broker.name == old_broker.name);
+            match new_broker {
+                Some(new_broker)
+                    if new_broker.rabbitmq.address() == old_broker.rabbitmq.address() => {}
+                Some(new_broker) => {
+                    log::info!(
+                        "Broker {} changed from {} to {}, dropping the state of its alerts",
+                        &old_broker.name,
+                        old_broker.rabbitmq.address(),
+                        new_broker.rabbitmq.address()
+                    );
+                    self.brokers.remove(&old_broker.name);
+                }
+                None => {
+                    log::info!(
+                        "Broker {} was removed, dropping the state of its alerts",
+                        &old_broker.name
+                    );
+                    self.brokers.remove(&old_broker.name);
+                }
+            }
+        }
+        for (name, client) in clients {
+            match self.brokers.get_mut(&name) {
+                Some(broker_state) => broker_state.client = client,
+                None => {
+                    self.brokers
+                        .insert(name, BrokerState::new(client, started_at));
+                }
+            }
+        }
+
+        let old_config = &self.config;
+        let brokers = &self.brokers;
+        self.sent_msgs_registry.retain(|key, _| {
+            let (broker_name, _, queue_name, trigger_type, _) = key;
+            if !brokers.contains_key(broker_name.as_str()) {
+                return false;
+            }
+            if trigger_type == BROKER_UNREACHABLE_TRIGGER_TYPE {
+                return old_config.broker_unreachable == config.broker_unreachable;
+            }
+            let unchanged = triggers_for_key(old_config, key) == triggers_for_key(&config, key);
+            if !unchanged {
+                log::debug!(
+                    "Trigger of type {} for {} on {} changed, dropping the state of its alerts",
+                    trigger_type,
+                    queue_name,
+                    broker_name
+                );
+            }
+            unchanged
+        });
+
+        self.metrics
+            .register_brokers(config.brokers.iter().map(|broker| broker.name.as_str()));
+        self.metrics.register_notifiers(notifiers.names());
+        self.config = config;
+        self.notifiers = notifiers;
+
+        Ok(())
+    }
+
+    /// The names of the brokers whose last poll failed
+    pub fn failed_brokers(&self) -> Vec<&str> {
+        self.config
+            .brokers
+            .iter()
+            .filter(|broker| {
+                self.brokers
+                    .get(&broker.name)
+                    .is_some_and(|state| state.poll_history.consecutive_failures > 0)
+            })
+            .map(|broker| broker.name.as_str())
+            .collect()
+    }
+
+    /// Polls the brokers once, evaluates the triggers and sends the alerts.
+    /// Returns the alerts which are firing, including the ones which were
+    /// already sent before. The state of the alerts is saved afterwards if
+    /// there's a state file.
+    pub async fn check(&mut self) -> Result<Vec<Alert>> {
+        let alerts = self.check_and_alert().await?;
+
+        if let Some(state_file) = self.state_file() {
+            if let Err(error) = save_state(state_file, &self.sent_msgs_registry) {
+                log::error!("Could not save the state of the alerts: {:#}", error);
+            }
+        }
+
+        Ok(alerts)
+    }
+
+    async fn check_and_alert(&mut self) -> Result<Vec<Alert>> {
+        let Monitor {
+            config,
+            notifiers,
+            metrics,
+            sent_msgs_registry,
+            brokers,
+            ..
+        } = self;
+        let mut polls = config
+            .brokers
+            .iter()
+            .filter_map(|broker| Some((broker, brokers.get(&broker.name)?)))
+            .map(|(broker, broker_state)| {
+                poll_broker(
+                    broker,
+                    broker_state.client.clone(),
+                    &config.settings.retry,
+                    broker_state.first_node(broker),
+                )
+                .map(move |poll| (broker, poll))
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        // Every broker is evaluated as soon as its poll is done, so a slow
+        // broker doesn't hold up the alerts of the others
+        let mut firing_alerts = Vec::new();
+        while let Some((broker, (poll_duration, result))) = polls.next().await {
+            let broker_state = match brokers.get_mut(&broker.name) {
+                Some(broker_state) => broker_state,
+                None => continue,
+            };
+            let current_ts = get_unix_timestamp()?;
+            let mut alerts = Vec::new();
+            let mut resolved_alerts = Vec::new();
+
+            match result {
+                Ok((node, queue_info)) => {
+                    broker_state.record_node(broker, node);
+                    broker_state.poll_history.record_success(current_ts);
+                    metrics.record_poll(&broker.name, poll_duration, current_ts, Some(&queue_info));
+                    log::debug!("Fetched queue info of {}: {:?}", &broker.name, queue_info);
+
+                    let (broker_alerts, breached_keys) = evaluate_triggers(
+                        broker,
+                        &queue_info,
+                        config.broker_triggers(broker),
+                        sent_msgs_registry,
+                        current_ts,
+                    );
+                    alerts.extend(broker_alerts);
+                    resolved_alerts.extend(
+                        resolve_breaches(sent_msgs_registry, &broker.name, &breached_keys)
+                            .into_iter()
+                            .map(|(key, breach)| {
+                                build_resolved_alert(broker, &key, &breach, current_ts)
+                            }),
+                    );
+                }
+                Err(error) => {
+                    broker_state.poll_history.record_failure();
+                    metrics.record_poll(&broker.name, poll_duration, current_ts, None);
+                    log::error!(
+                        "Could not fetch queue info of {} ({} consecutive failed polls), skipping this check: {:#}",
+                        &broker.name,
+                        &broker_state.poll_history.consecutive_failures,
+                        error,
+                    );
+
+                    if let Some(alert) =
+                        config
+                            .broker_unreachable
+                            .as_ref()
+                            .and_then(|unreachable_trigger| {
+                                build_broker_unreachable_alert(
+                                    broker,
+                                    unreachable_trigger,
+                                    &broker_state.poll_history,
+                                    broker_state.started_at,
+                                    current_ts,
+                                )
+                            })
+                    {
+                        record_breach(sent_msgs_registry, &alert, current_ts);
+                        alerts.push(alert);
+                    }
+                }
+            }
+
+            firing_alerts.extend(alerts.iter().cloned());
+            let alerts = filter_expired_alerts(
+                alerts,
+                sent_msgs_registry,
+                current_ts,
+                config.settings.msg_expiration_seconds,
+            );
+
+            send_alerts(
+                alerts.iter().chain(&resolved_alerts),
+                notifiers,
+                metrics,
+                current_ts,
+            )
+            .await;
+        }
+
+        Ok(firing_alerts)
+    }
+}
+
+fn filter_expired_alerts(
+    alerts: Vec<Alert>,
+    sent_msgs_registry: &mut MsgExpirationLog,
+    current_ts: UnixTimestamp,
+    expiration_in_seconds: u64,
+) -> Vec<Alert> {
+    alerts.into_iter()
+        .filter(|alert| {
+            match has_msg_expired(
+                sent_msgs_registry,
+                alert_key(alert),
+                alert.severity(),
+                current_ts,
+                expiration_in_seconds,
+            ) {
+                ExpirationStatus::Expired => {
+                    log::debug!(
+                        "Alert for {} of type {} has expired (expiration time is {}s). Resending...",
+                        alert.subject(),
+                        alert.trigger_type(),
+                        &expiration_in_seconds,
+                    );
+                    true
+                }
+                ExpirationStatus::NotExpired => {
+                    log::debug!(
+                        "Last alert for {} of type {} was sent too recently. Skipping sending this one...",
+                        alert.subject(),
+                        alert.trigger_type()
+                    );
+                    false
+                }
+                ExpirationStatus::Escalated => {
+                    log::debug!(
+                        "Alert for {} of type {} escalated to {}. Sending right away...",
+                        alert.subject(),
+                        alert.trigger_type(),
+                        alert.severity().map_or("", |severity| severity.name()),
+                    );
+                    true
+                }
+                ExpirationStatus::NotSentYet => {
+                    log::debug!(
+                        "Haven't yet sent an alert for {} of type {}. Saved in log.",
+                        alert.subject(),
+                        alert.trigger_type()
+                    );
+                    true
+                }
+            }
+        })
+        .collect()
+}
+
+/// Sends every alert with the notifiers of its route
+async fn send_alerts<'a>(
+    alerts: impl Iterator<Item = &'a Alert>,
+    notifiers: &Notifiers,
+    metrics: &Metrics,
+    current_ts: UnixTimestamp,
+) {
+    alerts
+        .flat_map(|alert| {
+            notifiers
+                .for_alert(alert)
+                .into_iter()
+                .map(move |notifier| (alert, notifier))
+        })
+        .map(|(alert, notifier)| {
+            notifier.notify(alert).map(move |result| {
+                metrics.record_notification(notifier.name(), current_ts, result.as_ref().err());
+                match result {
+                    Ok(_) => log::info!(
+                        "Sent {} notification about {}",
+                        notifier.name(),
+                        alert.subject()
+                    ),
+                    Err(e) => log::error!("Error sending {} notification: {}", notifier.name(), e),
+                };
+            })
+        })
+        .collect::<FuturesUnordered<_>>()
+        .collect::<Vec<()>>()
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    use crate::config::parse_config;
+
+    /// The ready messages of the queue, the API fails while it's `None`
+    type FakeQueue = Arc<Mutex<Option<u64>>>;
+
+    /// Serves a management API with a single queue on a free port
+    fn fake_management_api(queue: FakeQueue) -> u16 {
+        fake_management_api_at(("127.0.0.1", 0), queue)
+    }
+
+    /// Serves a management API with a single queue on the given address
+    fn fake_management_api_at(address: (&str, u16), queue: FakeQueue) -> u16 {
+        let listener = TcpListener::bind(address).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut app = tide::with_state(queue);
+        app.at("/api/queues")
+            .get(|req: tide::Request<FakeQueue>| async move {
+                let messages_ready = *req.state().lock().unwrap();
+                Ok(match messages_ready {
+                    Some(messages_ready) => tide::Response::builder(200)
+                        .body(serde_json::json!([{
+                            "name": "orders",
+                            "vhost": "/",
+                            "state": "running",
+                            "messages_ready": messages_ready,
+                        }]))
+                        .build(),
+                    None => tide::Response::new(500),
+                })
+            });
+        task::spawn(app.listen(listener));
+
+        port
+    }
+
+    const ORDERS_TRIGGER: &str = r#"
+        [[triggers]]
+        type = "messages_ready"
+        threshold = 10
+        queue = "orders"
+    "#;
+
+    /// A config for the fake management API with the given triggers
+    fn config(port: u16, triggers: &str) -> Config {
+        parse_config(&format!(
+            r#"
+            [rabbitmq]
+            protocol = "http"
+            host = "127.0.0.1"
+            port = "{}"
+            username = "guest"
+            password = "guest"
+
+            [settings]
+            poll_seconds = 1
+
+            [settings.retry]
+            max_retries = 0
+
+            [broker_unreachable]
+            failed_polls = 2
+
+            {}
+            "#,
+            port, triggers
+        ))
+        .unwrap()
+    }
+
+    /// A monitor of the fake management API with the given triggers
+    fn monitor(port: u16, triggers: &str) -> (Monitor, Arc<Mutex<Vec<Alert>>>) {
+        let (notifiers, alerts) = Notifiers::recording();
+        let monitor =
+            Monitor::new(config(port, triggers), notifiers, Arc::default(), false).unwrap();
+
+        (monitor, alerts)
+    }
+
+    /// Runs a check and returns the kind of the alerts sent, e.g. `threshold_passed`
+    async fn check(monitor: &mut Monitor, alerts: &Mutex<Vec<Alert>>) -> Vec<String> {
+        monitor.check().await.unwrap();
+        alerts
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|alert| {
+                alert.template_values()["kind"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn alerts_and_recovers() {
+        task::block_on(async {
+            let queue = FakeQueue::new(Mutex::new(Some(5)));
+            let (mut monitor, alerts) =
+                monitor(fake_management_api(Arc::clone(&queue)), ORDERS_TRIGGER);
+
+            assert!(check(&mut monitor, &alerts).await.is_empty());
+
+            *queue.lock().unwrap() = Some(20);
+            assert_eq!(check(&mut monitor, &alerts).await, ["threshold_passed"]);
+            assert!(check(&mut monitor, &alerts).await.is_empty());
+
+            *queue.lock().unwrap() = Some(5);
+            assert_eq!(check(&mut monitor, &alerts).await, ["threshold_resolved"]);
+
+            *queue.lock().unwrap() = Some(60);
+            assert_eq!(check(&mut monitor, &alerts).await, ["threshold_passed"]);
+
+            *queue.lock().unwrap() = Some(5);
+            assert_eq!(check(&mut monitor, &alerts).await, ["threshold_resolved"]);
+        })
+    }
+
+    #[test]
+    fn alerts_about_an_unreachable_broker() {
+        task::block_on(async {
+            let queue = FakeQueue::new(Mutex::new(None));
+            let (mut monitor, alerts) =
+                monitor(fake_management_api(Arc::clone(&queue)), ORDERS_TRIGGER);
+
+            assert!(check(&mut monitor, &alerts).await.is_empty());
+            assert_eq!(monitor.failed_brokers(), ["127.0.0.1"]);
+            assert_eq!(check(&mut monitor, &alerts).await, ["broker_unreachable"]);
+
+            *queue.lock().unwrap() = Some(5);
+            assert_eq!(check(&mut monitor, &alerts).await, ["broker_reachable"]);
+            assert!(monitor.failed_brokers().is_empty());
+        })
+    }
+
+    #[test]
+    fn overlapping_triggers_with_different_routes_alert_separately() {
+        task::block_on(async {
+            let queue = FakeQueue::new(Mutex::new(Some(20)));
+            let triggers = r#"
+                [routes.payments]
+                [routes.infra]
+
+                [[triggers]]
+                type = "messages_ready"
+                threshold = 10
+                queue_pattern = "ord*"
+                route = "payments"
+
+                [[triggers]]
+                type = "messages_ready"
+                threshold = 10
+                route = "infra"
+            "#;
+            let (mut monitor, alerts) = monitor(fake_management_api(Arc::clone(&queue)), triggers);
+            let routes = |alerts: &Mutex<Vec<Alert>>| {
+                let mut routes: Vec<_> = alerts
+                    .lock()
+                    .unwrap()
+                    .drain(..)
+                    .map(|alert| (alert.is_resolved(), alert.route().unwrap().to_string()))
+                    .collect();
+                routes.sort();
+                routes
+            };
+
+            monitor.check().await.unwrap();
+            assert_eq!(
+                routes(&alerts),
+                [
+                    (false, "infra".to_string()),
+                    (false, "payments".to_string())
+                ]
+            );
+
+            *queue.lock().unwrap() = Some(5);
+            monitor.check().await.unwrap();
+            assert_eq!(
+                routes(&alerts),
+                [(true, "infra".to_string()), (true, "payments".to_string())]
+            );
+        })
+    }
+
+    #[test]
+    fn reports_firing_alerts_which_were_already_sent() {
+        task::block_on(async {
+            let queue = FakeQueue::new(Mutex::new(Some(20)));
+            let (mut monitor, alerts) =
+                monitor(fake_management_api(Arc::clone(&queue)), ORDERS_TRIGGER);
+
+            assert_eq!(monitor.check().await.unwrap().len(), 1);
+            assert_eq!(alerts.lock().unwrap().drain(..).count(), 1);
+
+            assert_eq!(monitor.check().await.unwrap().len(), 1);
+            assert_eq!(alerts.lock().unwrap().drain(..).count(), 0);
+        })
+    }
+
+    #[test]
+    fn reload_only_resends_alerts_of_changed_triggers() {
+        task::block_on(async {
+            let queue = FakeQueue::new(Mutex::new(Some(20)));
+            let port = fake_management_api(Arc::clone(&queue));
+            let (mut monitor, alerts) = monitor(port, ORDERS_TRIGGER);
+            assert_eq!(check(&mut monitor, &alerts).await, ["threshold_passed"]);
+
+            let (notifiers, alerts) = Notifiers::recording();
+            monitor
+                .apply_config(config(port, ORDERS_TRIGGER), notifiers)
+                .unwrap();
+            assert!(check(&mut monitor, &alerts).await.is_empty());
+
+            let changed_trigger = ORDERS_TRIGGER.replace("threshold = 10", "threshold = 15");
+            let (notifiers, alerts) = Notifiers::recording();
+            monitor
+                .apply_config(config(port, &changed_trigger), notifiers)
+                .unwrap();
+            assert_eq!(check(&mut monitor, &alerts).await, ["threshold_passed"]);
+        })
+    }
+
+    #[test]
+    fn slow_broker_doesnt_hold_up_the_alerts_of_the_others() {
+        task::block_on(async {
+            let queue = FakeQueue::new(Mutex::new(Some(20)));
+            let port = fake_management_api(queue);
+            // Takes connections but never answers
+            let slow_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let slow_port = slow_listener.local_addr().unwrap().port();
+            let config = parse_config(&format!(
+                r#"
+                [[brokers]]
+                name = "slow"
+                protocol = "http"
+                host = "127.0.0.1"
+                port = "{}"
+                username = "guest"
+                password = "guest"
+
+                [[brokers]]
+                name = "fast"
+                protocol = "http"
+                host = "127.0.0.1"
+                port = "{}"
+                username = "guest"
+                password = "guest"
+
+                [settings]
+                poll_seconds = 1
+
+                [settings.retry]
+                max_retries = 0
+
+                {}
+                "#,
+                slow_port, port, ORDERS_TRIGGER
+            ))
+            .unwrap();
+            let (notifiers, alerts) = Notifiers::recording();
+            let mut monitor = Monitor::new(config, notifiers, Arc::default(), false).unwrap();
+
+            let check = async_std::future::timeout(Duration::from_millis(500), monitor.check());
+            assert!(check.await.is_err());
+            let alerts = alerts.lock().unwrap();
+            assert_eq!(alerts.len(), 1);
+            assert_eq!(alerts[0].broker_name(), "fast");
+        })
+    }
+
+    /// A monitor of a cluster with a fake management API on 127.0.0.1 and
+    /// 127.0.0.2, with the given failover
+    fn cluster_monitor(
+        first_queue: &FakeQueue,
+        second_queue: &FakeQueue,
+        failover: &str,
+    ) -> Monitor {
+        let port = fake_management_api(Arc::clone(first_queue));
+        fake_management_api_at(("127.0.0.2", port), Arc::clone(second_queue));
+        let config = parse_config(&format!(
+            r#"
+            [rabbitmq]
+            protocol = "http"
+            hosts = ["127.0.0.1", "127.0.0.2"]
+            failover = "{}"
+            port = "{}"
+            username = "guest"
+            password = "guest"
+
+            [settings]
+            poll_seconds = 1
+
+            [settings.retry]
+            max_retries = 0
+            "#,
+            failover, port
+        ))
+        .unwrap();
+        let (notifiers, _) = Notifiers::recording();
+
+        Monitor::new(config, notifiers, Arc::default(), false).unwrap()
+    }
+
+    /// The node the broker is polled at first next time
+    fn next_node(monitor: &Monitor) -> usize {
+        monitor.brokers["127.0.0.1"].node
+    }
+
+    #[test]
+    fn fails_over_in_order_and_sticks_to_the_node() {
+        task::block_on(async {
+            let first_queue = FakeQueue::new(Mutex::new(None));
+            let second_queue = FakeQueue::new(Mutex::new(Some(5)));
+            let mut monitor = cluster_monitor(&first_queue, &second_queue, "in_order");
+
+            monitor.check().await.unwrap();
+            assert!(monitor.failed_brokers().is_empty());
+            assert_eq!(next_node(&monitor), 1);
+
+            *first_queue.lock().unwrap() = Some(5);
+            monitor.check().await.unwrap();
+            assert_eq!(next_node(&monitor), 1);
+
+            *second_queue.lock().unwrap() = None;
+            monitor.check().await.unwrap();
+            assert!(monitor.failed_brokers().is_empty());
+            assert_eq!(next_node(&monitor), 0);
+        })
+    }
+
+    #[test]
+    fn round_robin_moves_on_to_the_next_node() {
+        task::block_on(async {
+            let first_queue = FakeQueue::new(Mutex::new(Some(5)));
+            let second_queue = FakeQueue::new(Mutex::new(Some(5)));
+            let mut monitor = cluster_monitor(&first_queue, &second_queue, "round_robin");
+
+            monitor.check().await.unwrap();
+            assert_eq!(next_node(&monitor), 1);
+            monitor.check().await.unwrap();
+            assert_eq!(next_node(&monitor), 0);
+
+            *first_queue.lock().unwrap() = None;
+            monitor.check().await.unwrap();
+            assert!(monitor.failed_brokers().is_empty());
+            assert_eq!(next_node(&monitor), 0);
+        })
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::alert::Alert;
+use crate::config::{Config, RouteConfig};
+use crate::slack::{PostedMsgs, SlackNotifier};
+use crate::webhook::WebhookNotifier;
+
+/// A way of delivering alerts, e.g. Slack or a webhook
+pub trait Notifier: Send + Sync {
+    /// Used in logs to tell the notifiers apart
+    fn name(&self) -> &str;
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Only logs the alerts, used when there's no other notifier configured
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn name(&self) -> &str {
+        "log"
+    }
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, Result<()>> {
+        log::info!("Alert about {}: {:?}", alert.subject(), alert);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Prints every alert as a line of JSON to stdout, used for dry runs
+pub struct JsonNotifier;
+
+impl Notifier for JsonNotifier {
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, Result<()>> {
+        println!("{}", JsonValue::Object(alert.template_values()));
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// All configured notifiers and the routes deciding which of them get an alert
+pub struct Notifiers {
+    notifiers: Vec<Box<dyn Notifier>>,
+    routes: HashMap<String, RouteConfig>,
+}
+
+impl Notifiers {
+    /// Only prints the alerts as JSON, ignoring the routes
+    pub fn json() -> Self {
+        Notifiers {
+            notifiers: vec![Box::new(JsonNotifier)],
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.notifiers.iter().map(|notifier| notifier.name())
+    }
+
+    /// The notifiers the alert is delivered with, all of them unless the
+    /// route of the alert names specific ones
+    pub fn for_alert(&self, alert: &Alert) -> Vec<&dyn Notifier> {
+        let route_notifiers = alert
+            .route()
+            .and_then(|route| self.routes.get(route))
+            .map(|route| &route.notifiers)
+            .filter(|route_notifiers| !route_notifiers.is_empty());
+
+        self.notifiers
+            .iter()
+            .map(|notifier| notifier.as_ref())
+            .filter(|notifier| {
+                route_notifiers.is_none_or(|route_notifiers| {
+                    route_notifiers.iter().any(|name| name == notifier.name())
+                })
+            })
+            .collect()
+    }
+}
+
+/// Builds a notifier for every backend configured, falls back to only
+/// logging the alerts if there are none. In a dry run the notifiers print
+/// what they would send instead of sending it. The Slack messages posted
+/// earlier are passed in, so recoveries still find their alerts after a reload.
+pub fn build_notifiers(config: &Config, dry_run: bool, posted_msgs: &PostedMsgs) -> Notifiers {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+
+    if let Some(slack_config) = &config.slack {
+        notifiers.push(Box::new(SlackNotifier::new(
+            slack_config.clone(),
+            config.routes.clone(),
+            dry_run,
+            Arc::clone(posted_msgs),
+        )));
+    }
+
+    for webhook_config in &config.webhooks {
+        notifiers.push(Box::new(WebhookNotifier::new(
+            webhook_config.clone(),
+            dry_run,
+        )));
+    }
+
+    if notifiers.is_empty() {
+        log::warn!("No notifiers configured, alerts will only be logged");
+        notifiers.push(Box::new(LogNotifier));
+    }
+
+    Notifiers {
+        notifiers,
+        routes: config.routes.clone(),
+    }
+}
+
+/// Keeps the alerts instead of delivering them, used to test the checks
+#[cfg(test)]
+pub struct RecordingNotifier {
+    alerts: std::sync::Arc<std::sync::Mutex<Vec<Alert>>>,
+}
+
+#[cfg(test)]
+impl Notifier for RecordingNotifier {
+    fn name(&self) -> &str {
+        "recording"
+    }
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, Result<()>> {
+        self.alerts.lock().unwrap().push(alert.clone());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+impl Notifiers {
+    /// Only records the alerts, returns the alerts it records as well
+    pub fn recording() -> (Self, std::sync::Arc<std::sync::Mutex<Vec<Alert>>>) {
+        let alerts = std::sync::Arc::default();
+        let notifiers = Notifiers {
+            notifiers: vec![Box::new(RecordingNotifier {
+                alerts: std::sync::Arc::clone(&alerts),
+            })],
+            routes: HashMap::new(),
+        };
+        (notifiers, alerts)
+    }
+}
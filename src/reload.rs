@@ -0,0 +1,49 @@
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Notices when the config file should be reloaded, either because it was
+/// modified or because the process received a SIGHUP
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    sighup: Arc<AtomicBool>,
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> Result<Self> {
+        let sighup = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&sighup))?;
+
+        Ok(ConfigWatcher {
+            path: path.to_path_buf(),
+            modified: modified_time(path),
+            sighup,
+        })
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Whether the config has to be reloaded since the last call
+    pub fn should_reload(&mut self) -> bool {
+        let received_sighup = self.sighup.swap(false, Ordering::Relaxed);
+
+        let modified = modified_time(&self.path);
+        let was_modified = modified.is_some() && modified != self.modified;
+        self.modified = modified;
+
+        received_sighup || was_modified
+    }
+}
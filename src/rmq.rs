@@ -1,23 +1,30 @@
 use anyhow::{anyhow, bail, Context, Result};
 use base64::engine::general_purpose::STANDARD as Base64StandardEngine;
 use base64::engine::Engine as _;
-use serde_derive::Deserialize;
+use futures::future::try_join_all;
+use http_client::isahc::IsahcClient;
+use isahc::config::{CaCertificate, ClientCertificate, Configurable, PrivateKey, SslOption};
+use serde_derive::{Deserialize, Serialize};
 use serde_json::{json, Value as JsonValue};
+use std::time::Duration;
 
-#[derive(Deserialize, Debug)]
+use crate::config::RabbitMqConfig;
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct QueueInfo {
     pub name: String,
+    pub vhost: String,
     pub state: String,
     pub stat: QueueStat,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct QueueStat {
     pub stat_type: StatType,
     pub value: f64,
 }
 
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub enum StatType {
     ConsumersTotal,
     MemoryTotal,
@@ -68,6 +75,67 @@ impl StatType {
         }
     }
 
+    /// The name and help text of the Prometheus metric
+    pub fn metric(&self) -> (&'static str, &'static str) {
+        match &self {
+            StatType::ConsumersTotal => ("rabbitmq_queue_consumers", "Number of consumers"),
+            StatType::MemoryTotal => ("rabbitmq_queue_memory_bytes", "Memory used by the queue"),
+            StatType::MessagesTotal => ("rabbitmq_queue_messages", "Total number of messages"),
+            StatType::MessagesReady => {
+                ("rabbitmq_queue_messages_ready", "Number of ready messages")
+            }
+            StatType::MessagesUnacknowledged => (
+                "rabbitmq_queue_messages_unacknowledged",
+                "Number of unacknowledged messages",
+            ),
+            StatType::MessagesTotalRate => (
+                "rabbitmq_queue_messages_rate",
+                "Rate of change of the total number of messages per second",
+            ),
+            StatType::MessagesReadyRate => (
+                "rabbitmq_queue_messages_ready_rate",
+                "Rate of change of the ready messages per second",
+            ),
+            StatType::MessagesUnacknowledgedRate => (
+                "rabbitmq_queue_messages_unacknowledged_rate",
+                "Rate of change of the unacknowledged messages per second",
+            ),
+            StatType::MessagesPublishRate => (
+                "rabbitmq_queue_messages_published_rate",
+                "Messages published per second",
+            ),
+            StatType::MessagesDeliveryRate => (
+                "rabbitmq_queue_messages_delivered_rate",
+                "Messages delivered per second",
+            ),
+            StatType::MessagesRedelivered => (
+                "rabbitmq_queue_messages_redelivered",
+                "Number of redelivered messages",
+            ),
+            StatType::MessagesRedeliverRate => (
+                "rabbitmq_queue_messages_redelivered_rate",
+                "Messages redelivered per second",
+            ),
+        }
+    }
+
+    pub fn unit(&self) -> Unit {
+        match &self {
+            StatType::MemoryTotal => Unit::Bytes,
+            StatType::ConsumersTotal
+            | StatType::MessagesTotal
+            | StatType::MessagesReady
+            | StatType::MessagesUnacknowledged
+            | StatType::MessagesRedelivered => Unit::Count,
+            StatType::MessagesTotalRate
+            | StatType::MessagesReadyRate
+            | StatType::MessagesUnacknowledgedRate
+            | StatType::MessagesPublishRate
+            | StatType::MessagesDeliveryRate
+            | StatType::MessagesRedeliverRate => Unit::PerSecond,
+        }
+    }
+
     fn to_str(&self) -> &str {
         match &self {
             StatType::ConsumersTotal => "ConsumersTotal",
@@ -86,22 +154,181 @@ impl StatType {
     }
 }
 
+/// What the value of a stat measures
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    Bytes,
+    Count,
+    PerSecond,
+}
+
+impl Unit {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Unit::Bytes => "bytes",
+            Unit::Count => "count",
+            Unit::PerSecond => "msg/s",
+        }
+    }
+
+    /// Formats the value for humans, e.g. `117.7 MiB`, `15000` or `12.35 msg/s`
+    pub fn format(&self, value: f64) -> String {
+        match *self {
+            Unit::Bytes => {
+                const PREFIXES: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+                let mut value = value;
+                let mut prefix = 0;
+                while value.abs() >= 1024.0 && prefix < PREFIXES.len() - 1 {
+                    value /= 1024.0;
+                    prefix += 1;
+                }
+                match prefix {
+                    0 => format!("{} {}", value, PREFIXES[prefix]),
+                    _ => format!("{:.1} {}", value, PREFIXES[prefix]),
+                }
+            }
+            Unit::Count if value.fract() == 0.0 => format!("{}", value),
+            Unit::Count => format!("{:.2}", value),
+            Unit::PerSecond => format!("{:.2} msg/s", value),
+        }
+    }
+}
+
 fn basic_auth_token(username: &str, password: &str) -> String {
     let combined = format!("{}:{}", username, password);
     let octet = combined.as_bytes();
     Base64StandardEngine.encode(octet)
 }
 
+/// Percent-encodes a vhost name for use as a path segment. The name is
+/// taken as is, a `%` in it is encoded as well.
+pub fn encode_vhost(vhost: &str) -> String {
+    vhost
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Builds the client for the management API with the TLS options of the
+/// connection. A request which takes longer than `timeout` fails, so a hanging
+/// API counts as unreachable.
+pub fn http_client(rmq_config: &RabbitMqConfig, timeout: Duration) -> Result<surf::Client> {
+    let mut builder = isahc::HttpClient::builder().timeout(timeout);
+
+    if let Some(ca_file) = &rmq_config.ca_file {
+        builder = builder.ssl_ca_certificate(CaCertificate::file(ca_file));
+    }
+    if let (Some(client_cert), Some(client_key)) = (&rmq_config.client_cert, &rmq_config.client_key)
+    {
+        builder = builder.ssl_client_certificate(ClientCertificate::pem_file(
+            client_cert,
+            PrivateKey::pem_file(client_key, None),
+        ));
+    }
+    if rmq_config.insecure_skip_verify {
+        log::warn!(
+            "Certificate verification is disabled for {}",
+            rmq_config.address()
+        );
+        builder = builder.ssl_options(
+            SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
+        );
+    }
+
+    let client = builder
+        .build()
+        .context("Could not build the HTTP client for the RabbitMQ API")?;
+    Ok(surf::Client::with_http_client(IsahcClient::from_client(
+        client,
+    )))
+}
+
+/// Fetches the queue info from the nodes of the cluster, starting with
+/// `first_node` and trying the next ones in order until one answers. Returns
+/// the index of the node which answered along with the queue info.
+pub async fn get_queue_info_with_failover(
+    client: &surf::Client,
+    rmq_config: &RabbitMqConfig,
+    first_node: usize,
+) -> Result<(usize, Vec<QueueInfo>)> {
+    let hosts = &rmq_config.hosts;
+    let mut last_error = anyhow!("No hosts configured");
+
+    for offset in 0..hosts.len() {
+        let node = (first_node + offset) % hosts.len();
+        match get_queue_info_for_vhosts(client, rmq_config, &hosts[node]).await {
+            Ok(queue_info) => return Ok((node, queue_info)),
+            Err(error) if hosts.len() > 1 => {
+                log::warn!(
+                    "Could not fetch queue info from node {}: {:#}",
+                    rmq_config.node_address(&hosts[node]),
+                    error
+                );
+                last_error = error.context(format!("All {} nodes failed", hosts.len()));
+            }
+            Err(error) => last_error = error,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Fetches the queue info for all vhosts configured for the connection from a node
+async fn get_queue_info_for_vhosts(
+    client: &surf::Client,
+    rmq_config: &RabbitMqConfig,
+    host: &str,
+) -> Result<Vec<QueueInfo>> {
+    let vhosts: Vec<Option<&str>> = match rmq_config.vhosts.names() {
+        Some(names) => names.into_iter().map(Some).collect(),
+        None => vec![None],
+    };
+
+    let password = rmq_config.password.value()?;
+    let queue_info = try_join_all(vhosts.into_iter().map(|vhost| {
+        get_queue_info(
+            client,
+            &rmq_config.protocol,
+            host,
+            &rmq_config.port,
+            &rmq_config.username,
+            &password,
+            vhost,
+        )
+    }))
+    .await?;
+
+    Ok(queue_info.into_iter().flatten().collect())
+}
+
 pub async fn get_queue_info(
+    client: &surf::Client,
     protocol: &str,
     host: &str,
     port: &str,
     username: &str,
     password: &str,
+    vhost: Option<&str>,
 ) -> Result<Vec<QueueInfo>> {
-    let url = format!("{}://{}:{}/api/queues", protocol, host, port);
+    let url = match vhost {
+        Some(vhost) => format!(
+            "{}://{}:{}/api/queues/{}",
+            protocol,
+            host,
+            port,
+            encode_vhost(vhost)
+        ),
+        None => format!("{}://{}:{}/api/queues", protocol, host, port),
+    };
     let token = basic_auth_token(username, password);
-    let mut response = match surf::get(url)
+    let mut response = match client
+        .get(url)
         .header("Authorization", format!("Basic {}", token))
         .await
     {
@@ -157,6 +384,7 @@ fn build_queue_info_json_values(rmq_api_queue_item: &JsonValue) -> Result<Vec<Js
 
         queue_info.push(json!({
             "name": rmq_api_queue_item.get("name"),
+            "vhost": rmq_api_queue_item.get("vhost"),
             "state": rmq_api_queue_item.get("state"),
             "stat": {
                 "stat_type": k.to_str(),
@@ -189,4 +417,24 @@ fn preprocess_queues_info_json(json: &mut JsonValue) -> Option<JsonValue> {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_vhost_escapes_reserved_characters() {
+        assert_eq!(encode_vhost("/"), "%2F");
+        assert_eq!(encode_vhost("billing"), "billing");
+        assert_eq!(encode_vhost("my vhost"), "my%20vhost");
+        assert_eq!(encode_vhost("100%"), "100%25");
+        assert_eq!(encode_vhost("%2f"), "%252f");
+    }
+
+    #[test]
+    fn unit_format() {
+        assert_eq!(Unit::Bytes.format(512.0), "512 B");
+        assert_eq!(Unit::Bytes.format(123456789.0), "117.7 MiB");
+        assert_eq!(Unit::Count.format(15000.0), "15000");
+        assert_eq!(Unit::Count.format(2.5), "2.50");
+        assert_eq!(Unit::PerSecond.format(12.345), "12.35 msg/s");
+    }
+}
@@ -0,0 +1,71 @@
+use anyhow::Result;
+use std::sync::Arc;
+use tide::{Request, Response, StatusCode};
+
+use crate::config::ServerConfig;
+use crate::metrics::{Health, Metrics};
+use crate::utils::{get_unix_timestamp, UnixTimestamp};
+
+#[derive(Clone)]
+struct ServerState {
+    metrics: Arc<Metrics>,
+    max_poll_age_seconds: u64,
+    started_at: UnixTimestamp,
+}
+
+impl ServerState {
+    fn health(&self) -> tide::Result<Health> {
+        Ok(self.metrics.health(
+            get_unix_timestamp()?,
+            self.max_poll_age_seconds,
+            self.started_at,
+        ))
+    }
+}
+
+fn health_response(ok: bool, health: &Health) -> tide::Result {
+    let status = if ok {
+        StatusCode::Ok
+    } else {
+        StatusCode::ServiceUnavailable
+    };
+
+    Ok(Response::builder(status)
+        .body(tide::Body::from_json(health)?)
+        .build())
+}
+
+/// Serves the `/metrics`, `/healthz` and `/readyz` endpoints until the process exits
+pub async fn serve(config: ServerConfig, poll_seconds: u64, metrics: Arc<Metrics>) -> Result<()> {
+    let mut app = tide::with_state(ServerState {
+        metrics,
+        max_poll_age_seconds: config.max_poll_age(poll_seconds),
+        started_at: get_unix_timestamp()?,
+    });
+
+    app.at("/metrics")
+        .get(|req: Request<ServerState>| async move {
+            Ok(Response::builder(StatusCode::Ok)
+                .content_type("text/plain; version=0.0.4")
+                .body(req.state().metrics.render())
+                .build())
+        });
+    app.at("/healthz")
+        .get(|req: Request<ServerState>| async move {
+            let health = req.state().health()?;
+            health_response(health.healthy, &health)
+        });
+    app.at("/readyz")
+        .get(|req: Request<ServerState>| async move {
+            let health = req.state().health()?;
+            health_response(health.ready, &health)
+        });
+
+    log::info!(
+        "Serving metrics and health checks on http://{}",
+        &config.listen
+    );
+    app.listen(config.listen).await?;
+
+    Ok(())
+}
@@ -1,7 +1,17 @@
 use anyhow::{anyhow, bail, Result};
-use serde::Serializer;
-use serde_derive::Serialize;
-use std::sync::Arc;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+
+use crate::alert::Alert;
+use crate::config::{RouteConfig, Secret, Severity, SlackConfig, SlackFormat, SlackResolveMode};
+use crate::notifier::Notifier;
+use crate::rmq::encode_vhost;
+use crate::template::render_template;
+use crate::utils::{alert_key, get_unix_timestamp, AlertKey, UnixTimestamp};
 
 #[derive(Serialize, Debug, Clone)]
 pub struct SlackMsg {
@@ -11,31 +21,499 @@ pub struct SlackMsg {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub icon_emoji: Option<String>,
     pub channel: String,
-
-    #[serde(serialize_with = "slack_metadata_to_msg_text", rename = "text")]
-    pub metadata: SlackMsgMetadata,
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachments: Option<Vec<JsonValue>>,
+    /// Posts the message as a reply in the thread, only for the Web API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_ts: Option<String>,
+    /// The message to replace with `chat.update`, only for the Web API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ts: Option<String>,
 }
 
-fn slack_metadata_to_msg_text<S>(metadata: &SlackMsgMetadata, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    let text = format!(
-        "Queue *{name}* has passed a threshold of {threshold} {trigger_type}. Currently at *{number}*.",
-        name = metadata.queue_name,
-        threshold = metadata.threshold,
-        trigger_type = metadata.trigger_type,
-        number = metadata.current_value,
-    );
-    s.serialize_str(&text)
+#[derive(Deserialize, Debug)]
+struct SlackApiResponse {
+    ok: bool,
+    error: Option<String>,
+    channel: Option<String>,
+    ts: Option<String>,
 }
 
+/// A message posted through the Web API, identified by the channel ID and its timestamp
 #[derive(Debug, Clone)]
-pub struct SlackMsgMetadata {
-    pub queue_name: String,
-    pub threshold: f64,
-    pub current_value: f64,
-    pub trigger_type: String,
+pub struct PostedMsg {
+    channel: String,
+    ts: String,
+}
+
+/// The alerts posted through the Web API which haven't been resolved yet.
+/// Kept outside of the notifier so they outlive a reload of the config.
+pub type PostedMsgs = Arc<Mutex<HashMap<AlertKey, PostedMsg>>>;
+
+/// Builds the text of the message, prefixed with the severity of the alert
+/// and the given mention, e.g. `<!here>`. The template replaces the default
+/// text of firing alerts, the default text links to the runbook if there's one.
+fn alert_to_msg_text(alert: &Alert, mention: Option<&str>, template: Option<&str>) -> String {
+    let text = match (alert, template) {
+        (_, Some(template)) if !alert.is_resolved() => {
+            render_template(template, &alert.template_values(), false)
+        }
+        (
+            Alert::ThresholdPassed {
+                broker_name,
+                queue_name,
+                vhost,
+                threshold,
+                current_value,
+                unit,
+                trigger_type,
+                trigger_when,
+                ..
+            },
+            _,
+        ) => format!(
+            "Queue *{name}* on vhost *{vhost}* of *{broker_name}*: {trigger_type} {crossed} the threshold of {threshold}. Currently at *{number}*.",
+            name = queue_name,
+            vhost = vhost,
+            broker_name = broker_name,
+            trigger_type = trigger_type,
+            crossed = trigger_when.crossed(),
+            threshold = unit.format(*threshold),
+            number = unit.format(*current_value),
+        ),
+        (
+            Alert::BrokerUnreachable {
+                broker,
+                broker_name,
+                failed_polls,
+                seconds_without_data,
+                ..
+            },
+            _,
+        ) => format!(
+            "RabbitMQ management API of *{broker_name}* at {broker} is unreachable. {failed_polls} consecutive polls have failed, no data for *{seconds}s*.",
+            broker_name = broker_name,
+            broker = broker,
+            failed_polls = failed_polls,
+            seconds = seconds_without_data,
+        ),
+        (
+            Alert::ThresholdResolved {
+                broker_name,
+                queue_name,
+                vhost,
+                trigger_type,
+                breach_seconds,
+                peak_value,
+                unit,
+                ..
+            },
+            _,
+        ) => format!(
+            "Queue *{name}* on vhost *{vhost}* of *{broker_name}* is back to normal {trigger_type} after {duration}. Peak value was *{peak}*.",
+            name = queue_name,
+            vhost = vhost,
+            broker_name = broker_name,
+            trigger_type = trigger_type,
+            duration = format_duration(*breach_seconds),
+            peak = unit.format(*peak_value),
+        ),
+        (
+            Alert::BrokerReachable {
+                broker,
+                broker_name,
+                unreachable_seconds,
+                ..
+            },
+            _,
+        ) => format!(
+            "RabbitMQ management API of *{broker_name}* at {broker} is reachable again after {duration}.",
+            broker_name = broker_name,
+            broker = broker,
+            duration = format_duration(*unreachable_seconds),
+        ),
+    };
+
+    let text = match (alert.runbook_url(), template) {
+        (_, Some(_)) if !alert.is_resolved() => text,
+        (Some(runbook_url), _) => format!("{} <{}|Runbook>", text, runbook_url),
+        (None, _) => text,
+    };
+
+    let prefix = match alert.severity() {
+        Some(Severity::Warning) => ":warning: *Warning*",
+        Some(Severity::Critical) => ":rotating_light: *Critical*",
+        None => ":white_check_mark: *Resolved*",
+    };
+
+    match mention {
+        Some(mention) => format!("{} {} {}", mention, prefix, text),
+        None => format!("{} {}", prefix, text),
+    }
+}
+
+fn severity_color(alert: &Alert) -> &'static str {
+    match alert.severity() {
+        Some(Severity::Warning) => "#f2c744",
+        Some(Severity::Critical) => "#d40e0d",
+        None => "#2eb886",
+    }
+}
+
+fn block_field(name: &str, value: impl std::fmt::Display) -> JsonValue {
+    json!({
+        "type": "mrkdwn",
+        "text": format!("*{}*\n{}", name, value),
+    })
+}
+
+/// Builds the Block Kit layout of the alert, wrapped in an attachment to
+/// color code it by severity
+fn alert_to_attachment(alert: &Alert, ts: UnixTimestamp) -> JsonValue {
+    let status = alert
+        .severity()
+        .map_or("Resolved", |severity| match severity {
+            Severity::Warning => "Warning",
+            Severity::Critical => "Critical",
+        });
+
+    let (header, fields, queue_url) = match alert {
+        Alert::ThresholdPassed {
+            broker,
+            queue_name,
+            vhost,
+            threshold,
+            current_value,
+            trigger_type,
+            trigger_when,
+            unit,
+            ..
+        } => (
+            format!("{}: {} on {}", status, trigger_type, queue_name),
+            vec![
+                block_field("Queue", queue_name),
+                block_field("Vhost", vhost),
+                block_field("Broker", alert.broker_name()),
+                block_field("Metric", trigger_type),
+                block_field("Threshold", unit.format(*threshold)),
+                block_field("Current value", unit.format(*current_value)),
+                block_field("Direction", trigger_when.name()),
+            ],
+            Some(format!(
+                "{}/#/queues/{}/{}",
+                broker,
+                encode_vhost(vhost),
+                encode_vhost(queue_name)
+            )),
+        ),
+        Alert::ThresholdResolved {
+            broker,
+            queue_name,
+            vhost,
+            trigger_type,
+            breach_seconds,
+            peak_value,
+            unit,
+            ..
+        } => (
+            format!("{}: {} on {}", status, trigger_type, queue_name),
+            vec![
+                block_field("Queue", queue_name),
+                block_field("Vhost", vhost),
+                block_field("Broker", alert.broker_name()),
+                block_field("Metric", trigger_type),
+                block_field("Peak value", unit.format(*peak_value)),
+                block_field("Breach duration", format_duration(*breach_seconds)),
+            ],
+            Some(format!(
+                "{}/#/queues/{}/{}",
+                broker,
+                encode_vhost(vhost),
+                encode_vhost(queue_name)
+            )),
+        ),
+        Alert::BrokerUnreachable {
+            broker_name,
+            failed_polls,
+            seconds_without_data,
+            ..
+        } => (
+            format!("{}: {}", status, alert.trigger_type()),
+            vec![
+                block_field("Broker", broker_name),
+                block_field("Failed polls", failed_polls),
+                block_field("No data for", format_duration(*seconds_without_data)),
+            ],
+            None,
+        ),
+        Alert::BrokerReachable {
+            broker_name,
+            unreachable_seconds,
+            ..
+        } => (
+            format!("{}: {}", status, alert.trigger_type()),
+            vec![
+                block_field("Broker", broker_name),
+                block_field("Unreachable for", format_duration(*unreachable_seconds)),
+            ],
+            None,
+        ),
+    };
+
+    let mut blocks = vec![
+        json!({
+            "type": "header",
+            "text": { "type": "plain_text", "text": header },
+        }),
+        json!({
+            "type": "section",
+            "fields": fields,
+        }),
+    ];
+
+    let mut buttons = Vec::new();
+    if let Some(queue_url) = queue_url {
+        buttons.push(json!({
+            "type": "button",
+            "text": { "type": "plain_text", "text": "Open in management UI" },
+            "url": queue_url,
+        }));
+    }
+    if let Some(runbook_url) = alert.runbook_url() {
+        buttons.push(json!({
+            "type": "button",
+            "text": { "type": "plain_text", "text": "Runbook" },
+            "url": runbook_url,
+        }));
+    }
+    if !buttons.is_empty() {
+        blocks.push(json!({
+            "type": "actions",
+            "elements": buttons,
+        }));
+    }
+
+    blocks.push(json!({
+        "type": "context",
+        "elements": [{
+            "type": "mrkdwn",
+            "text": format!(
+                "{} ({}) | <!date^{ts}^{{date_short_pretty}} {{time_secs}}|{ts}>",
+                alert.broker_name(),
+                alert.broker(),
+                ts = ts
+            ),
+        }],
+    }));
+
+    json!({
+        "color": severity_color(alert),
+        "blocks": blocks,
+    })
+}
+
+/// Formats a number of seconds as e.g. `1h 5m 30s`
+fn format_duration(seconds: u64) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds % 3600 / 60, seconds % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", seconds),
+        (0, _) => format!("{}m {}s", minutes, seconds),
+        _ => format!("{}h {}m {}s", hours, minutes, seconds),
+    }
+}
+
+pub struct SlackNotifier {
+    config: SlackConfig,
+    routes: HashMap<String, RouteConfig>,
+    /// Prints the messages instead of sending them
+    dry_run: bool,
+    posted_msgs: PostedMsgs,
+}
+
+impl SlackNotifier {
+    pub fn new(
+        config: SlackConfig,
+        routes: HashMap<String, RouteConfig>,
+        dry_run: bool,
+        posted_msgs: PostedMsgs,
+    ) -> Self {
+        SlackNotifier {
+            config,
+            routes,
+            dry_run,
+            posted_msgs,
+        }
+    }
+
+    fn route(&self, alert: &Alert) -> Option<&RouteConfig> {
+        alert.route().and_then(|route| self.routes.get(route))
+    }
+
+    fn channel(&self, alert: &Alert) -> &str {
+        self.route(alert)
+            .and_then(|route| route.slack_channel.as_deref())
+            .unwrap_or(&self.config.channel)
+    }
+
+    fn webhook_url(&self, alert: &Alert) -> Option<&Secret> {
+        self.route(alert)
+            .and_then(|route| route.slack_webhook_url.as_ref())
+            .or(self.config.webhook_url.as_ref())
+    }
+
+    fn build_msg(&self, alert: &Alert) -> SlackMsg {
+        let mention = match alert.severity() {
+            Some(Severity::Warning) => self.config.warning_mention.as_deref(),
+            Some(Severity::Critical) => self.config.critical_mention.as_deref(),
+            None => None,
+        };
+
+        SlackMsg {
+            username: self.config.screen_name.clone(),
+            channel: format!("#{}", self.channel(alert)),
+            icon_url: self.config.icon_url.clone(),
+            icon_emoji: self.config.icon_emoji.clone(),
+            text: alert_to_msg_text(
+                alert,
+                mention,
+                alert
+                    .message_template()
+                    .or(self.config.message_template.as_deref()),
+            ),
+            attachments: match self.config.format {
+                SlackFormat::Text => None,
+                SlackFormat::Blocks => Some(vec![alert_to_attachment(
+                    alert,
+                    get_unix_timestamp().unwrap_or_default(),
+                )]),
+            },
+            thread_ts: None,
+            ts: None,
+        }
+    }
+
+    /// Posts the alert with the Web API. Recoveries are threaded onto or
+    /// replace the original alert if it was posted by this notifier.
+    async fn send_with_bot_token(&self, bot_token: &str, alert: &Alert) -> Result<()> {
+        let key = alert_key(alert);
+        let mut msg = self.build_msg(alert);
+
+        let original_msg = if alert.is_resolved() {
+            self.posted_msgs
+                .lock()
+                .expect("Slack posted messages lock poisoned")
+                .remove(&key)
+        } else {
+            None
+        };
+
+        let method = match (original_msg, self.config.on_resolve) {
+            (Some(original_msg), SlackResolveMode::Thread) => {
+                msg.thread_ts = Some(original_msg.ts);
+                "chat.postMessage"
+            }
+            (Some(original_msg), SlackResolveMode::Update) => {
+                msg.channel = original_msg.channel;
+                msg.ts = Some(original_msg.ts);
+                "chat.update"
+            }
+            _ => "chat.postMessage",
+        };
+
+        let response = call_slack_api(&self.config.api_url, method, bot_token, &msg).await?;
+        log::debug!("Slack message body {:?} sent with {}", msg, method);
+
+        if !alert.is_resolved() {
+            if let (Some(channel), Some(ts)) = (response.channel, response.ts) {
+                self.posted_msgs
+                    .lock()
+                    .expect("Slack posted messages lock poisoned")
+                    .insert(key, PostedMsg { channel, ts });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn name(&self) -> &str {
+        "slack"
+    }
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            if self.dry_run {
+                let msg = self.build_msg(alert);
+                println!(
+                    "Dry run, Slack message to {}:\n{}",
+                    &msg.channel,
+                    serde_json::to_string_pretty(&msg)?
+                );
+                return Ok(());
+            }
+
+            match (&self.config.bot_token, self.webhook_url(alert)) {
+                (Some(bot_token), _) => self.send_with_bot_token(&bot_token.value()?, alert).await,
+                (None, Some(webhook_url)) => {
+                    let msg = Arc::new(self.build_msg(alert));
+                    send_slack_msg(&webhook_url.value()?, Arc::clone(&msg)).await?;
+                    log::debug!("Slack message body {:?} sent to {}", msg, msg.channel);
+                    Ok(())
+                }
+                (None, None) => bail!("Slack config needs either a webhook_url or a bot_token"),
+            }
+        })
+    }
+}
+
+async fn call_slack_api(
+    api_url: &str,
+    method: &str,
+    bot_token: &str,
+    msg: &SlackMsg,
+) -> Result<SlackApiResponse> {
+    let url = format!("{}/{}", api_url.trim_end_matches('/'), method);
+    let mut response = match surf::post(url)
+        .header("Authorization", format!("Bearer {}", bot_token))
+        .body_json(msg)
+        .map_err(anyhow::Error::msg)?
+        .await
+    {
+        Ok(response) => response,
+        Err(error) => bail!(error),
+    };
+
+    let body_string = match response.body_string().await {
+        Ok(body_string) => body_string,
+        Err(error) => bail!(error),
+    };
+
+    if response.status() != 200 {
+        return Err(anyhow!(
+            "Slack API Error: HTTP {} {}",
+            response.status(),
+            body_string
+        ));
+    }
+
+    let api_response: SlackApiResponse = serde_json::from_str(&body_string).map_err(|error| {
+        anyhow!(
+            "Slack API Error: invalid response {}: {}",
+            body_string,
+            error
+        )
+    })?;
+    if !api_response.ok {
+        return Err(anyhow!(
+            "Slack API Error: {} failed with {}",
+            method,
+            api_response.error.as_deref().unwrap_or("unknown error")
+        ));
+    }
+
+    Ok(api_response)
 }
 
 pub async fn send_slack_msg(webhook_url: &str, msg: Arc<SlackMsg>) -> Result<()> {
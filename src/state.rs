@@ -0,0 +1,184 @@
+use anyhow::{bail, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::utils::{AlertState, MsgExpirationLog};
+
+const STATE_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    version: u32,
+    alerts: Vec<StateEntry>,
+}
+
+/// The state of the alerts for a single key, JSON objects can't have tuples as keys
+#[derive(Serialize, Deserialize)]
+struct StateEntry {
+    broker: String,
+    vhost: String,
+    queue: String,
+    trigger_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    route: Option<String>,
+    #[serde(flatten)]
+    state: AlertState,
+}
+
+/// Loads the state of the alerts saved by a previous run, a missing file is an empty state
+pub fn load_state(path: &Path) -> Result<MsgExpirationLog> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(MsgExpirationLog::new()),
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("Could not read state file {}", path.display()))
+        }
+    };
+
+    let state_file: StateFile = serde_json::from_str(&contents)
+        .with_context(|| format!("Could not parse state file {}", path.display()))?;
+    if state_file.version != STATE_VERSION {
+        bail!(
+            "State file {} has version {}, expected {}",
+            path.display(),
+            state_file.version,
+            STATE_VERSION
+        );
+    }
+
+    Ok(state_file
+        .alerts
+        .into_iter()
+        .map(|entry| {
+            let key = (
+                SmolStr::new(entry.broker),
+                SmolStr::new(entry.vhost),
+                SmolStr::new(entry.queue),
+                SmolStr::new(entry.trigger_type),
+                SmolStr::new(entry.route.unwrap_or_default()),
+            );
+            (key, entry.state)
+        })
+        .collect())
+}
+
+/// Saves the state of the alerts. It's written to a temporary file first
+/// and then moved in place, so the file is never left half written.
+pub fn save_state(path: &Path, msg_expiration_log: &MsgExpirationLog) -> Result<()> {
+    let state_file = StateFile {
+        version: STATE_VERSION,
+        alerts: msg_expiration_log
+            .iter()
+            .map(
+                |((broker, vhost, queue, trigger_type, route), state)| StateEntry {
+                    broker: broker.to_string(),
+                    vhost: vhost.to_string(),
+                    queue: queue.to_string(),
+                    trigger_type: trigger_type.to_string(),
+                    route: (!route.is_empty()).then(|| route.to_string()),
+                    state: state.clone(),
+                },
+            )
+            .collect(),
+    };
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, serde_json::to_string_pretty(&state_file)?)
+        .with_context(|| format!("Could not write state file {}", path.display()))?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Could not write state file {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::config::Severity;
+    use crate::rmq::Unit;
+    use crate::utils::{AlertKey, Breach};
+
+    /// A state file in the temp dir which is removed again when dropped
+    struct TempStateFile(PathBuf);
+
+    impl TempStateFile {
+        fn new(name: &str) -> Self {
+            let file_name = format!("rmq_monitor-{}-{}.json", std::process::id(), name);
+            TempStateFile(std::env::temp_dir().join(file_name))
+        }
+    }
+
+    impl Drop for TempStateFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn key(route: &str) -> AlertKey {
+        (
+            SmolStr::new("main"),
+            SmolStr::new("/"),
+            SmolStr::new("orders"),
+            SmolStr::new("messages_ready"),
+            SmolStr::new(route),
+        )
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let state_file = TempStateFile::new("round_trip");
+        let mut log = MsgExpirationLog::new();
+        log.insert(
+            key("payments"),
+            AlertState {
+                last_sent: Some(100),
+                last_sent_severity: Some(Severity::Critical),
+                breach: Some(Breach {
+                    started_at: 90,
+                    route: Some("payments".to_string()),
+                    peak_value: 20.0,
+                    unit: Unit::Count,
+                    notified: true,
+                }),
+            },
+        );
+        log.insert(key(""), AlertState::default());
+
+        save_state(&state_file.0, &log).unwrap();
+        let loaded = load_state(&state_file.0).unwrap();
+
+        assert_eq!(loaded.len(), 2);
+        let state = &loaded[&key("payments")];
+        assert_eq!(state.last_sent, Some(100));
+        assert_eq!(state.last_sent_severity, Some(Severity::Critical));
+        let breach = state.breach.as_ref().unwrap();
+        assert_eq!(breach.started_at, 90);
+        assert_eq!(breach.route.as_deref(), Some("payments"));
+        assert_eq!(breach.peak_value, 20.0);
+        assert_eq!(breach.unit, Unit::Count);
+        assert!(breach.notified);
+        assert!(loaded[&key("")].breach.is_none());
+    }
+
+    #[test]
+    fn missing_state_file_is_empty() {
+        let state_file = TempStateFile::new("missing");
+        assert!(load_state(&state_file.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn state_file_of_another_version_is_rejected() {
+        let state_file = TempStateFile::new("version");
+        fs::write(&state_file.0, r#"{"version": 1, "alerts": []}"#).unwrap();
+
+        let error = load_state(&state_file.0).unwrap_err();
+        assert!(error.to_string().contains("has version 1, expected 2"));
+    }
+}
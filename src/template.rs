@@ -0,0 +1,142 @@
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+/// Replaces the `{name}` placeholders in the template with the values of the
+/// same name. Placeholders without a value are left as they are. With
+/// `as_json` the template is taken to be JSON: inside a JSON string the
+/// values are escaped and `null` is left empty, elsewhere they're inserted as
+/// JSON. Otherwise they're inserted as they are, `null` as an empty string
+/// and numbers without a trailing `.0`.
+pub fn render_template(
+    template: &str,
+    values: &JsonMap<String, JsonValue>,
+    as_json: bool,
+) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    let mut in_string = false;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        in_string = ends_in_json_string(in_string, &rest[..start]);
+        let placeholder = &rest[start..];
+
+        let value = placeholder
+            .find('}')
+            .and_then(|end| Some((end, values.get(&placeholder[1..end])?)));
+        match value {
+            Some((end, value)) => {
+                rendered.push_str(&render_value(value, as_json, in_string));
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+
+    rendered
+}
+
+/// Whether a JSON string is still open after the text, given whether one
+/// was open before it
+fn ends_in_json_string(mut in_string: bool, text: &str) -> bool {
+    let mut escaped = false;
+    for c in text.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ => {}
+        }
+    }
+    in_string
+}
+
+fn render_value(value: &JsonValue, as_json: bool, in_string: bool) -> String {
+    match value {
+        JsonValue::String(string) if as_json && in_string => {
+            let quoted = JsonValue::String(string.clone()).to_string();
+            quoted[1..quoted.len() - 1].to_string()
+        }
+        JsonValue::Null if as_json && in_string => String::new(),
+        _ if as_json => value.to_string(),
+        JsonValue::String(string) => string.clone(),
+        JsonValue::Null => String::new(),
+        JsonValue::Number(number) => number
+            .as_f64()
+            .map_or_else(|| number.to_string(), |number| number.to_string()),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn values() -> JsonMap<String, JsonValue> {
+        json!({
+            "queue": "orders \"eu\"",
+            "value": 20.0,
+            "threshold": 10.5,
+            "runbook_url": null,
+        })
+        .as_object()
+        .cloned()
+        .unwrap()
+    }
+
+    #[test]
+    fn render_template_as_text() {
+        assert_eq!(
+            render_template("{queue} has {value} > {threshold}", &values(), false),
+            "orders \"eu\" has 20 > 10.5"
+        );
+        assert_eq!(
+            render_template("Runbook: {runbook_url}", &values(), false),
+            "Runbook: "
+        );
+    }
+
+    #[test]
+    fn render_template_as_json() {
+        assert_eq!(
+            render_template(
+                r#"{"queue": "{queue}", "value": {value}, "runbook": {runbook_url}}"#,
+                &values(),
+                true
+            ),
+            r#"{"queue": "orders \"eu\"", "value": 20.0, "runbook": null}"#
+        );
+    }
+
+    #[test]
+    fn render_template_as_json_by_position() {
+        assert_eq!(
+            render_template(
+                r#"{"title": "{queue} at {runbook_url}", "queue": {queue}, "runbook": {runbook_url}}"#,
+                &values(),
+                true
+            ),
+            r#"{"title": "orders \"eu\" at ", "queue": "orders \"eu\"", "runbook": null}"#
+        );
+        assert_eq!(
+            render_template(
+                r#"{"text": "a \"{value}\" b", "value": {value}}"#,
+                &values(),
+                true
+            ),
+            r#"{"text": "a \"20.0\" b", "value": 20.0}"#
+        );
+    }
+
+    #[test]
+    fn render_template_keeps_unknown_placeholders() {
+        assert_eq!(
+            render_template("{unknown} {queue", &values(), false),
+            "{unknown} {queue"
+        );
+    }
+}
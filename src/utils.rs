@@ -1,18 +1,56 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
+use async_std::task;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
 use smol_str::SmolStr;
-use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::config::{SlackConfig, Trigger, TriggerData, TriggerWhen};
-use crate::rmq::{QueueInfo, QueueStat};
-use crate::slack::{SlackMsg, SlackMsgMetadata};
+use crate::alert::{Alert, BROKER_UNREACHABLE_TRIGGER_TYPE};
+use crate::config::{
+    BrokerConfig, BrokerUnreachableTrigger, RetryPolicy, Severity, Trigger, TriggerData,
+    TriggerWhen,
+};
+use crate::rmq::{QueueInfo, Unit};
 
-pub fn check_trigger_applicability(trigger: &Trigger, queue_name: &str, stat: &QueueStat) -> bool {
-    if let Some(trigger_queue_name) = &trigger.data().queue {
-        trigger_queue_name == queue_name && trigger.stat_type() == stat.stat_type
-    } else {
-        trigger.stat_type() == stat.stat_type
+pub fn check_trigger_applicability(trigger: &Trigger, queue_info: &QueueInfo) -> bool {
+    trigger.stat_type() == queue_info.stat.stat_type
+        && trigger_matches_queue(trigger, &queue_info.vhost, &queue_info.name)
+}
+
+/// Whether the trigger covers the queue, regardless of the stat type
+pub fn trigger_matches_queue(trigger: &Trigger, vhost: &str, queue_name: &str) -> bool {
+    if let Some(trigger_vhost) = &trigger.data().vhost {
+        if trigger_vhost != vhost {
+            return false;
+        }
+    }
+
+    let trigger_data = trigger.data();
+
+    if let Some(trigger_queue_name) = &trigger_data.queue {
+        if trigger_queue_name != queue_name {
+            return false;
+        }
+    }
+
+    if let Some(queue_pattern) = &trigger_data.queue_pattern {
+        if !queue_pattern.matches(queue_name) {
+            return false;
+        }
+    }
+
+    if let Some(queue_regex) = &trigger_data.queue_regex {
+        if !queue_regex.is_match(queue_name) {
+            return false;
+        }
     }
+
+    !trigger_data
+        .exclude
+        .iter()
+        .any(|pattern| pattern.matches(queue_name))
 }
 
 pub type UnixTimestamp = u64;
@@ -21,72 +59,622 @@ pub fn get_unix_timestamp() -> Result<UnixTimestamp> {
     Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
 }
 
+/// Keeps track of how the recent polls of the RabbitMQ API went
+#[derive(Debug, Default)]
+pub struct PollHistory {
+    pub consecutive_failures: u32,
+    pub last_success: Option<UnixTimestamp>,
+}
+
+impl PollHistory {
+    pub fn record_success(&mut self, ts: UnixTimestamp) {
+        self.consecutive_failures = 0;
+        self.last_success = Some(ts);
+    }
+
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+    }
+}
+
+fn backoff_delay(retry_policy: &RetryPolicy, attempt: u32) -> Duration {
+    let backoff_ms = retry_policy
+        .initial_backoff_ms
+        .saturating_mul(2u64.saturating_pow(attempt))
+        .min(retry_policy.max_backoff_ms);
+
+    if retry_policy.jitter && backoff_ms > 1 {
+        Duration::from_millis(rand::thread_rng().gen_range(backoff_ms / 2..=backoff_ms))
+    } else {
+        Duration::from_millis(backoff_ms)
+    }
+}
+
+/// Runs the given operation, retrying it with exponential backoff according
+/// to the retry policy. The error of the last attempt is returned.
+pub async fn with_retries<T, F, Fut>(retry_policy: &RetryPolicy, mut operation: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt < retry_policy.max_retries => {
+                let delay = backoff_delay(retry_policy, attempt);
+                attempt += 1;
+                log::warn!(
+                    "Attempt {} of {} failed: {:#}. Retrying in {}ms...",
+                    attempt,
+                    retry_policy.max_retries + 1,
+                    error,
+                    delay.as_millis(),
+                );
+                task::sleep(delay).await;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
 pub enum ExpirationStatus {
     Expired,
     NotSentYet,
     NotExpired,
+    Escalated,
 }
 
+pub type BrokerName = SmolStr;
+pub type VhostName = SmolStr;
 pub type QueueName = SmolStr;
 pub type TriggerType = SmolStr;
-pub type MsgExpirationLog = HashMap<(QueueName, TriggerType), UnixTimestamp>;
+/// Empty for alerts without a route
+pub type RouteName = SmolStr;
+pub type AlertKey = (BrokerName, VhostName, QueueName, TriggerType, RouteName);
+pub type MsgExpirationLog = HashMap<AlertKey, AlertState>;
+
+/// What is known about the alerts for a single (broker, vhost, queue, trigger type, route)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AlertState {
+    pub last_sent: Option<UnixTimestamp>,
+    pub last_sent_severity: Option<Severity>,
+    pub breach: Option<Breach>,
+}
 
+/// A trigger which is currently firing
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Breach {
+    pub started_at: UnixTimestamp,
+    /// The route of the alert, the recovery is delivered through it as well
+    pub route: Option<String>,
+    pub peak_value: f64,
+    pub unit: Unit,
+    /// Whether an alert was sent for this breach, only then a recovery is sent
+    pub notified: bool,
+}
+
+pub fn alert_key(alert: &Alert) -> AlertKey {
+    (
+        SmolStr::new(alert.broker_name()),
+        SmolStr::new(alert.vhost()),
+        SmolStr::new(alert.subject()),
+        SmolStr::new(alert.trigger_type()),
+        SmolStr::new(alert.route().unwrap_or_default()),
+    )
+}
+
+/// A message which is more severe than the last one sent is escalated
+/// right away, regardless of the expiration time
 pub fn has_msg_expired(
     msg_expiration_log: &mut MsgExpirationLog,
-    queue_trigger_type: (QueueName, TriggerType),
+    queue_trigger_type: AlertKey,
+    severity: Option<Severity>,
     current_ts: UnixTimestamp,
     expiration_in_seconds: u64,
-) -> Result<ExpirationStatus> {
-    match msg_expiration_log.get(&queue_trigger_type) {
-        Some(ts) => {
-            if ts + expiration_in_seconds < current_ts {
-                *msg_expiration_log
-                    .get_mut(&queue_trigger_type)
-                    .expect("No such entry in sent msgs log") = current_ts;
-                Ok(ExpirationStatus::Expired)
-            } else {
-                Ok(ExpirationStatus::NotExpired)
+) -> ExpirationStatus {
+    let alert_state = msg_expiration_log.entry(queue_trigger_type).or_default();
+    let status = match alert_state.last_sent {
+        Some(ts) if ts + expiration_in_seconds < current_ts => ExpirationStatus::Expired,
+        Some(_) if severity > alert_state.last_sent_severity => ExpirationStatus::Escalated,
+        Some(_) => return ExpirationStatus::NotExpired,
+        None => ExpirationStatus::NotSentYet,
+    };
+
+    alert_state.last_sent = Some(current_ts);
+    alert_state.last_sent_severity = severity;
+    if let Some(breach) = alert_state.breach.as_mut() {
+        breach.notified = true;
+    }
+    status
+}
+
+/// Starts a breach for the alert or updates the peak value of the ongoing one
+pub fn record_breach(
+    msg_expiration_log: &mut MsgExpirationLog,
+    alert: &Alert,
+    current_ts: UnixTimestamp,
+) {
+    let (value, trigger_when, started_at) = match alert {
+        Alert::ThresholdPassed {
+            current_value,
+            trigger_when,
+            ..
+        } => (*current_value, *trigger_when, current_ts),
+        Alert::BrokerUnreachable {
+            failed_polls,
+            seconds_without_data,
+            ..
+        } => (
+            *failed_polls as f64,
+            TriggerWhen::Above,
+            current_ts.saturating_sub(*seconds_without_data),
+        ),
+        _ => return,
+    };
+
+    let alert_state = msg_expiration_log.entry(alert_key(alert)).or_default();
+    match alert_state.breach.as_mut() {
+        Some(breach) => {
+            breach.peak_value = match trigger_when {
+                TriggerWhen::Above => breach.peak_value.max(value),
+                TriggerWhen::Below => breach.peak_value.min(value),
             }
         }
         None => {
-            msg_expiration_log.insert(
-                queue_trigger_type,
-                get_unix_timestamp().context("Cannot get UNIX timestamp")?,
-            );
-            Ok(ExpirationStatus::NotSentYet)
+            alert_state.breach = Some(Breach {
+                started_at,
+                route: alert.route().map(String::from),
+                peak_value: value,
+                unit: alert.unit(),
+                notified: false,
+            })
         }
     }
 }
 
+/// Ends all breaches of the broker whose keys aren't in `breached_keys`
+/// anymore. Returns the ended breaches which were notified about. Their
+/// last sent alert is forgotten, so the next breach is alerted about right
+/// away. Keys with nothing left to remember are dropped from the log.
+pub fn resolve_breaches(
+    msg_expiration_log: &mut MsgExpirationLog,
+    broker_name: &str,
+    breached_keys: &HashSet<AlertKey>,
+) -> Vec<(AlertKey, Breach)> {
+    let mut resolved = Vec::new();
+
+    msg_expiration_log.retain(|key, alert_state| {
+        if key.0 != broker_name || breached_keys.contains(key) {
+            return true;
+        }
+        if let Some(breach) = alert_state.breach.take() {
+            if breach.notified {
+                alert_state.last_sent = None;
+                alert_state.last_sent_severity = None;
+                resolved.push((key.clone(), breach));
+            }
+        }
+        alert_state.last_sent.is_some()
+    });
+
+    resolved
+}
+
 fn is_threshold_passed(stat_value: f64, trigger_data: &TriggerData) -> bool {
+    trigger_data
+        .trigger_when
+        .passes(stat_value, trigger_data.threshold())
+}
+
+/// Values in a breach which are short of the critical threshold are warnings,
+/// unless the trigger only has a critical threshold
+fn severity_for_value(stat_value: f64, trigger_data: &TriggerData) -> Severity {
+    match (trigger_data.warning, trigger_data.critical) {
+        (Some(_), Some(critical)) if trigger_data.trigger_when.passes(stat_value, critical) => {
+            Severity::Critical
+        }
+        (Some(_), _) => Severity::Warning,
+        (None, _) => Severity::Critical,
+    }
+}
+
+/// Whether an ongoing breach has ended, which is when the value crosses back
+/// past `clear_threshold` or the threshold itself if there's none
+fn is_threshold_cleared(stat_value: f64, trigger_data: &TriggerData) -> bool {
+    let clear_threshold = trigger_data
+        .clear_threshold
+        .unwrap_or_else(|| trigger_data.threshold());
     match trigger_data.trigger_when {
-        TriggerWhen::Above => stat_value > trigger_data.threshold,
-        TriggerWhen::Below => stat_value < trigger_data.threshold,
+        TriggerWhen::Above => stat_value <= clear_threshold,
+        TriggerWhen::Below => stat_value >= clear_threshold,
+    }
+}
+
+fn is_breach_sustained(
+    msg_expiration_log: &MsgExpirationLog,
+    key: &AlertKey,
+    for_seconds: Option<u64>,
+    current_ts: UnixTimestamp,
+) -> bool {
+    let breach = msg_expiration_log
+        .get(key)
+        .and_then(|alert_state| alert_state.breach.as_ref());
+    match (breach, for_seconds) {
+        (Some(breach), Some(for_seconds)) => breach.started_at + for_seconds <= current_ts,
+        _ => true,
     }
 }
 
-pub fn build_msgs_for_trigger(
+pub fn build_alerts_for_trigger(
+    broker: &BrokerConfig,
     queue_info: &[QueueInfo],
     trigger: &Trigger,
-    slack_config: &SlackConfig,
-) -> Vec<SlackMsg> {
-    let msgs: Vec<SlackMsg> = queue_info
+    msg_expiration_log: &MsgExpirationLog,
+) -> Vec<Alert> {
+    let alerts: Vec<Alert> = queue_info
         .iter()
-        .filter(|qi| check_trigger_applicability(trigger, &qi.name, &qi.stat))
-        .filter(|qi| is_threshold_passed(qi.stat.value, trigger.data()))
-        .map(|qi| SlackMsg {
-            username: slack_config.screen_name.clone(),
-            channel: format!("#{}", &slack_config.channel),
-            icon_url: slack_config.icon_url.clone(),
-            icon_emoji: slack_config.icon_emoji.clone(),
-            metadata: SlackMsgMetadata {
+        .filter(|qi| check_trigger_applicability(trigger, qi))
+        .filter(|qi| {
+            let key: AlertKey = (
+                SmolStr::new(&broker.name),
+                SmolStr::new(&qi.vhost),
+                SmolStr::new(&qi.name),
+                SmolStr::new(trigger.name()),
+                SmolStr::new(trigger.data().route.as_deref().unwrap_or_default()),
+            );
+            let in_breach = msg_expiration_log
+                .get(&key)
+                .is_some_and(|alert_state| alert_state.breach.is_some());
+            if in_breach {
+                !is_threshold_cleared(qi.stat.value, trigger.data())
+            } else {
+                is_threshold_passed(qi.stat.value, trigger.data())
+            }
+        })
+        .map(|qi| {
+            let severity = severity_for_value(qi.stat.value, trigger.data());
+            Alert::ThresholdPassed {
+                broker: broker.rabbitmq.address(),
+                broker_name: broker.name.clone(),
                 queue_name: qi.name.clone(),
-                threshold: trigger.data().threshold,
+                vhost: qi.vhost.clone(),
+                threshold: trigger.data().severity_threshold(severity),
                 current_value: qi.stat.value,
+                unit: trigger.stat_type().unit(),
                 trigger_type: trigger.name().into(),
-            },
+                trigger_when: trigger.data().trigger_when,
+                severity,
+                route: trigger.data().route.clone(),
+                message_template: trigger.data().message_template.clone(),
+                runbook_url: trigger.data().runbook_url.clone(),
+            }
         })
         .collect();
 
-    msgs
+    alerts
+}
+
+/// Evaluates all triggers against the queue info and records the breaches.
+/// Returns the alerts for breaches which lasted long enough to alert about
+/// and the keys of all breaches, including the ones still pending.
+pub fn evaluate_triggers(
+    broker: &BrokerConfig,
+    queue_info: &[QueueInfo],
+    triggers: &[Trigger],
+    msg_expiration_log: &mut MsgExpirationLog,
+    current_ts: UnixTimestamp,
+) -> (Vec<Alert>, HashSet<AlertKey>) {
+    let mut alerts = Vec::new();
+    let mut breached_keys = HashSet::new();
+
+    for trigger in triggers {
+        for alert in build_alerts_for_trigger(broker, queue_info, trigger, msg_expiration_log) {
+            record_breach(msg_expiration_log, &alert, current_ts);
+            let key = alert_key(&alert);
+            if is_breach_sustained(
+                msg_expiration_log,
+                &key,
+                trigger.data().for_seconds,
+                current_ts,
+            ) {
+                alerts.push(alert);
+            } else {
+                log::debug!(
+                    "Threshold for {} of type {} is passed but not for long enough yet",
+                    alert.subject(),
+                    alert.trigger_type(),
+                );
+            }
+            breached_keys.insert(key);
+        }
+    }
+
+    (alerts, breached_keys)
+}
+
+fn is_broker_unreachable(
+    trigger: &BrokerUnreachableTrigger,
+    consecutive_failures: u32,
+    seconds_without_data: u64,
+) -> bool {
+    let too_many_failures = trigger
+        .failed_polls
+        .is_some_and(|failed_polls| consecutive_failures >= failed_polls);
+    let no_data_for_too_long = trigger
+        .no_data_seconds
+        .is_some_and(|no_data_seconds| seconds_without_data >= no_data_seconds);
+
+    too_many_failures || no_data_for_too_long
+}
+
+/// Builds an alert if the broker has been unreachable for long enough.
+/// `started_at` is used instead of the last successful poll if there's none yet.
+pub fn build_broker_unreachable_alert(
+    broker: &BrokerConfig,
+    trigger: &BrokerUnreachableTrigger,
+    poll_history: &PollHistory,
+    started_at: UnixTimestamp,
+    current_ts: UnixTimestamp,
+) -> Option<Alert> {
+    let seconds_without_data =
+        current_ts.saturating_sub(poll_history.last_success.unwrap_or(started_at));
+
+    if !is_broker_unreachable(
+        trigger,
+        poll_history.consecutive_failures,
+        seconds_without_data,
+    ) {
+        return None;
+    }
+
+    Some(Alert::BrokerUnreachable {
+        broker: broker.rabbitmq.address(),
+        broker_name: broker.name.clone(),
+        failed_polls: poll_history.consecutive_failures,
+        seconds_without_data,
+        route: trigger.route.clone(),
+        message_template: trigger.message_template.clone(),
+        runbook_url: trigger.runbook_url.clone(),
+    })
+}
+
+/// Builds the recovery alert for a breach which has ended
+pub fn build_resolved_alert(
+    broker: &BrokerConfig,
+    key: &AlertKey,
+    breach: &Breach,
+    current_ts: UnixTimestamp,
+) -> Alert {
+    let (_, vhost, subject, trigger_type, _) = key;
+    let breach_seconds = current_ts.saturating_sub(breach.started_at);
+
+    if trigger_type == BROKER_UNREACHABLE_TRIGGER_TYPE {
+        Alert::BrokerReachable {
+            broker: broker.rabbitmq.address(),
+            broker_name: broker.name.clone(),
+            unreachable_seconds: breach_seconds,
+            route: breach.route.clone(),
+        }
+    } else {
+        Alert::ThresholdResolved {
+            broker: broker.rabbitmq.address(),
+            broker_name: broker.name.clone(),
+            queue_name: subject.to_string(),
+            vhost: vhost.to_string(),
+            trigger_type: trigger_type.to_string(),
+            breach_seconds,
+            peak_value: breach.peak_value,
+            unit: breach.unit,
+            route: breach.route.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn threshold_alert(current_value: f64) -> Alert {
+        Alert::ThresholdPassed {
+            broker: "http://localhost:15672".into(),
+            broker_name: "main".into(),
+            queue_name: "orders".into(),
+            vhost: "/".into(),
+            threshold: 10.0,
+            current_value,
+            unit: Unit::Count,
+            trigger_type: "messages_ready".into(),
+            trigger_when: TriggerWhen::Above,
+            severity: Severity::Critical,
+            route: None,
+            message_template: None,
+            runbook_url: None,
+        }
+    }
+
+    #[test]
+    fn msg_expiration() {
+        let mut log = MsgExpirationLog::new();
+        let key = alert_key(&threshold_alert(20.0));
+        let mut status_at =
+            |severity, ts| has_msg_expired(&mut log, key.clone(), severity, ts, 600);
+
+        assert!(matches!(
+            status_at(Some(Severity::Warning), 100),
+            ExpirationStatus::NotSentYet
+        ));
+        assert!(matches!(
+            status_at(Some(Severity::Warning), 400),
+            ExpirationStatus::NotExpired
+        ));
+        assert!(matches!(
+            status_at(Some(Severity::Warning), 701),
+            ExpirationStatus::Expired
+        ));
+    }
+
+    #[test]
+    fn more_severe_msg_is_escalated() {
+        let mut log = MsgExpirationLog::new();
+        let key = alert_key(&threshold_alert(20.0));
+        let mut status_at =
+            |severity, ts| has_msg_expired(&mut log, key.clone(), severity, ts, 600);
+
+        assert!(matches!(
+            status_at(Some(Severity::Warning), 100),
+            ExpirationStatus::NotSentYet
+        ));
+        assert!(matches!(
+            status_at(Some(Severity::Critical), 400),
+            ExpirationStatus::Escalated
+        ));
+        assert!(matches!(
+            status_at(Some(Severity::Critical), 500),
+            ExpirationStatus::NotExpired
+        ));
+        assert!(matches!(
+            status_at(Some(Severity::Warning), 600),
+            ExpirationStatus::NotExpired
+        ));
+    }
+
+    #[test]
+    fn unnotified_breach_resolves_silently() {
+        let mut log = MsgExpirationLog::new();
+        record_breach(&mut log, &threshold_alert(20.0), 100);
+
+        assert!(resolve_breaches(&mut log, "main", &HashSet::new()).is_empty());
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn breach_after_recovery_alerts_right_away() {
+        let mut log = MsgExpirationLog::new();
+        let alert = threshold_alert(20.0);
+        let key = alert_key(&alert);
+
+        record_breach(&mut log, &alert, 100);
+        assert!(matches!(
+            has_msg_expired(&mut log, key.clone(), alert.severity(), 100, 600),
+            ExpirationStatus::NotSentYet
+        ));
+
+        let resolved = resolve_breaches(&mut log, "main", &HashSet::new());
+        assert_eq!(resolved.len(), 1);
+        assert!(log.is_empty());
+
+        let alert = threshold_alert(60.0);
+        record_breach(&mut log, &alert, 110);
+        assert!(matches!(
+            has_msg_expired(&mut log, key.clone(), alert.severity(), 110, 600),
+            ExpirationStatus::NotSentYet
+        ));
+        assert!(log[&key]
+            .breach
+            .as_ref()
+            .is_some_and(|breach| breach.notified));
+
+        let resolved = resolve_breaches(&mut log, "main", &HashSet::new());
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].1.peak_value, 60.0);
+    }
+
+    fn broker() -> BrokerConfig {
+        toml::from_str(
+            r#"
+            name = "main"
+            host = "localhost"
+            port = "15672"
+            username = "guest"
+            password = "guest"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn ready_messages(value: f64) -> Vec<QueueInfo> {
+        vec![QueueInfo {
+            name: "orders".into(),
+            vhost: "/".into(),
+            state: "running".into(),
+            stat: crate::rmq::QueueStat {
+                stat_type: crate::rmq::StatType::MessagesReady,
+                value,
+            },
+        }]
+    }
+
+    /// Evaluates the trigger against the value, returns the number of alerts
+    /// and whether the queue is still in breach
+    fn evaluate(
+        trigger: &Trigger,
+        log: &mut MsgExpirationLog,
+        value: f64,
+        current_ts: UnixTimestamp,
+    ) -> (usize, bool) {
+        let (alerts, breached_keys) = evaluate_triggers(
+            &broker(),
+            &ready_messages(value),
+            std::slice::from_ref(trigger),
+            log,
+            current_ts,
+        );
+        (alerts.len(), !breached_keys.is_empty())
+    }
+
+    #[test]
+    fn breach_ends_past_clear_threshold() {
+        let trigger: Trigger = toml::from_str(
+            r#"
+            type = "messages_ready"
+            threshold = 100
+            clear_threshold = 80
+            "#,
+        )
+        .unwrap();
+        let mut log = MsgExpirationLog::new();
+
+        assert_eq!(evaluate(&trigger, &mut log, 90.0, 0), (0, false));
+        assert_eq!(evaluate(&trigger, &mut log, 120.0, 10), (1, true));
+        assert_eq!(evaluate(&trigger, &mut log, 90.0, 20), (1, true));
+        assert_eq!(evaluate(&trigger, &mut log, 80.0, 30), (0, false));
+    }
+
+    #[test]
+    fn breach_alerts_after_for_seconds() {
+        let trigger: Trigger = toml::from_str(
+            r#"
+            type = "messages_ready"
+            threshold = 100
+            for_seconds = 60
+            "#,
+        )
+        .unwrap();
+        let mut log = MsgExpirationLog::new();
+
+        assert_eq!(evaluate(&trigger, &mut log, 120.0, 100), (0, true));
+        assert_eq!(evaluate(&trigger, &mut log, 120.0, 159), (0, true));
+        assert_eq!(evaluate(&trigger, &mut log, 120.0, 160), (1, true));
+    }
+
+    #[test]
+    fn breach_shorter_than_for_seconds_resolves_silently() {
+        let trigger: Trigger = toml::from_str(
+            r#"
+            type = "messages_ready"
+            threshold = 100
+            for_seconds = 60
+            "#,
+        )
+        .unwrap();
+        let mut log = MsgExpirationLog::new();
+
+        assert_eq!(evaluate(&trigger, &mut log, 120.0, 100), (0, true));
+        assert_eq!(evaluate(&trigger, &mut log, 50.0, 130), (0, false));
+        assert!(resolve_breaches(&mut log, "main", &HashSet::new()).is_empty());
+
+        assert_eq!(evaluate(&trigger, &mut log, 120.0, 140), (0, true));
+        assert_eq!(evaluate(&trigger, &mut log, 120.0, 199), (0, true));
+    }
 }
@@ -0,0 +1,99 @@
+use anyhow::{anyhow, bail, Result};
+use futures::future::BoxFuture;
+use std::str::FromStr;
+use surf::http::{headers::HeaderName, mime};
+
+use crate::alert::Alert;
+use crate::config::WebhookConfig;
+use crate::notifier::Notifier;
+use crate::template::render_template;
+use crate::utils::with_retries;
+
+/// POSTs alerts as JSON to an arbitrary URL
+pub struct WebhookNotifier {
+    config: WebhookConfig,
+    /// Prints the requests instead of sending them
+    dry_run: bool,
+}
+
+impl WebhookNotifier {
+    pub fn new(config: WebhookConfig, dry_run: bool) -> Self {
+        WebhookNotifier { config, dry_run }
+    }
+
+    /// Fills in the body template, or sends all values of the alert as a JSON
+    /// object if there's no template
+    fn build_body(&self, alert: &Alert) -> String {
+        let values = alert.template_values();
+        match &self.config.body_template {
+            Some(template) => render_template(template, &values, true),
+            None => serde_json::Value::Object(values).to_string(),
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let body = self.build_body(alert);
+            if self.dry_run {
+                let pretty_body = serde_json::from_str::<serde_json::Value>(&body)
+                    .and_then(|json| serde_json::to_string_pretty(&json))
+                    .unwrap_or_else(|_| body.clone());
+                println!(
+                    "Dry run, webhook {} would POST:\n{}",
+                    &self.config.name, pretty_body
+                );
+                return Ok(());
+            }
+
+            with_retries(&self.config.retry, || {
+                send_webhook(&self.config, body.clone())
+            })
+            .await?;
+            log::debug!("Webhook body {} sent to {}", body, &self.config.name);
+            Ok(())
+        })
+    }
+}
+
+pub async fn send_webhook(config: &WebhookConfig, body: String) -> Result<()> {
+    let mut request = surf::post(config.url.value()?)
+        .body_string(body)
+        .content_type(mime::JSON);
+    for (name, value) in &config.headers {
+        let name = HeaderName::from_str(name).map_err(anyhow::Error::msg)?;
+        request = request.header(name, value.value()?);
+    }
+
+    let mut response = match request.await {
+        Ok(response) => response,
+        Err(error) => bail!(error),
+    };
+
+    let status: u16 = response.status().into();
+    let is_expected = if config.expected_status.is_empty() {
+        response.status().is_success()
+    } else {
+        config.expected_status.contains(&status)
+    };
+
+    if !is_expected {
+        let body_string = match response.body_string().await {
+            Ok(body_string) => body_string,
+            Err(error) => bail!(error),
+        };
+        return Err(anyhow!(
+            "Webhook {} Error: HTTP {} {}",
+            &config.name,
+            status,
+            body_string
+        ));
+    }
+
+    Ok(())
+}